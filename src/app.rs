@@ -1,16 +1,23 @@
+use crate::blacklist::Blacklist;
 use crate::checker_thread::checker_thread;
 use crate::config::*;
-use crate::options::Opt;
+use crate::export::ExportFormat;
+use crate::geoip::GeoIp;
+use crate::import::parse_proxies;
+use crate::options::{Command, Opt};
 use crate::proxy_pool::*;
 use crate::server::MyState;
 use crate::spider::getter::*;
+use crate::spider::proxy::Protocol;
+use crate::spider::utils::set_api_password;
 use crate::spider_thread::spider_thread;
 
 use app_dirs::*;
 use failure::{format_err, Error};
 use lazy_static::lazy_static;
-use log::{debug, info};
+use log::{debug, error, info};
 use structopt::StructOpt;
+use tokio::runtime::Runtime;
 
 use std::fs::File;
 use std::io::prelude::*;
@@ -34,6 +41,8 @@ lazy_static! {
         debug!("data_path: {}", data_path.display());
         data_path
     };
+    // 黑名单和 proxies.json 放在一起
+    static ref BLACKLIST_PATH: PathBuf = DATA_PATH.with_file_name("blacklist.json");
 }
 
 fn init_proxy_pool() -> Result<AProxyPool, Error> {
@@ -41,12 +50,63 @@ fn init_proxy_pool() -> Result<AProxyPool, Error> {
     info!("正在读取缓存");
 
     // 存在 proxies.json 的话, 读取 & 反序列化之
-    if Path::new(&*DATA_PATH).exists() {
-        let proxy_pool = serde_json::from_reader(File::open(&*DATA_PATH)?)?;
-        Ok(Arc::new(proxy_pool))
+    // 旧版本的 Ipv4Addr/SocketAddrV4 与 IpAddr/SocketAddr 序列化格式相同, 可以直接读取
+    let proxy_pool = if Path::new(&*DATA_PATH).exists() {
+        let proxy_pool: ProxyPool = serde_json::from_reader(File::open(&*DATA_PATH)?)?;
+        proxy_pool.init_index();
+        proxy_pool.init_schedule();
+        proxy_pool
     } else {
-        Ok(Arc::new(ProxyPool::new()))
+        ProxyPool::new()
+    };
+    proxy_pool.set_blacklist(Blacklist::load(&*BLACKLIST_PATH)?);
+    Ok(Arc::new(proxy_pool))
+}
+
+fn save_proxy_pool(proxy_pool: &AProxyPool) -> Result<(), Error> {
+    let data = serde_json::to_string_pretty(proxy_pool)?;
+    let mut file = File::create(&*DATA_PATH)?;
+    file.write_all(data.as_bytes())?;
+    Ok(())
+}
+
+fn import_file(
+    file: &str,
+    format: Option<String>,
+    protocol: &str,
+    source: &str,
+) -> Result<(), Error> {
+    let data =
+        std::fs::read_to_string(file).map_err(|e| format_err!("无法读取文件 {}: {}", file, e))?;
+    let format = format.map(|format| format.parse()).transpose()?;
+    Protocol::parse_strict(protocol)?;
+    let (proxies, invalid) = parse_proxies(&data, format, protocol)?;
+    let total = proxies.len();
+
+    let proxy_pool = init_proxy_pool()?;
+    let added = proxy_pool.clone().extend_unstable(proxies, source);
+    save_proxy_pool(&proxy_pool)?;
+    println!(
+        "导入 {} 个代理, 跳过 {} 个已存在或被封禁的代理, {} 个无法解析",
+        added,
+        total - added,
+        invalid
+    );
+    Ok(())
+}
+
+fn export_proxies(format: &str, filter: Filter) -> Result<(), Error> {
+    let format: ExportFormat = format.parse()?;
+    let proxy_pool = init_proxy_pool()?;
+    let proxies = proxy_pool.select(filter);
+    let output = format.render(&proxies);
+    // JSON 的结尾没有换行
+    if output.ends_with('\n') {
+        print!("{}", output);
+    } else {
+        println!("{}", output);
     }
+    Ok(())
 }
 
 fn init_config(config_file: Option<&String>) -> Result<Config, Error> {
@@ -64,6 +124,7 @@ fn init_config(config_file: Option<&String>) -> Result<Config, Error> {
 
 fn test_proxy(config_file: Option<String>, rule_name: &str) {
     let config = init_config(config_file.as_ref()).expect("解析配置文件错误");
+    set_api_password(&config.password);
 
     for rules in &config.spider.common_table {
         let CommonTable {
@@ -96,7 +157,7 @@ fn test_proxy(config_file: Option<String>, rule_name: &str) {
             ip,
             port,
             anonymity,
-            ssl_type,
+            protocol,
             ..
         } = rules;
 
@@ -104,7 +165,7 @@ fn test_proxy(config_file: Option<String>, rule_name: &str) {
             continue;
         }
 
-        match regex_getter(name, urls, ip, port, anonymity, ssl_type) {
+        match regex_getter(name, urls, ip, port, anonymity, protocol) {
             Err(e) => eprintln!("{}", e),
             Ok(v) => {
                 for proxy in &v {
@@ -128,30 +189,99 @@ pub fn run() -> Result<(), Error> {
         return Ok(());
     }
 
+    match args.cmd {
+        Some(Command::Import {
+            file,
+            format,
+            protocol,
+            source,
+        }) => return import_file(&file, format, &protocol, &source),
+        Some(Command::Export {
+            format,
+            protocol,
+            anonymity,
+            stability,
+            max_latency,
+            target,
+            country,
+            asn,
+        }) => {
+            let filter = Filter::parse(
+                protocol,
+                anonymity,
+                stability,
+                max_latency,
+                target,
+                country,
+                asn,
+            )?;
+            return export_proxies(&format, filter);
+        }
+        None => (),
+    }
+
     let proxy_pool = init_proxy_pool()?;
     let reload = Arc::new(RwLock::new(false));
-    let password = Arc::new(RwLock::new(None));
+    let runtime_config = Arc::new(RwLock::new(RuntimeConfig::default()));
 
     // 启动 server
     let server = {
         let proxy_pool = proxy_pool.clone();
         let reload = reload.clone();
-        let password = password.clone();
-        thread::spawn(|| crate::server::launch_rocket(MyState::new(proxy_pool, reload, password)))
+        let runtime_config = runtime_config.clone();
+        thread::spawn(|| {
+            crate::server::launch_rocket(MyState::new(proxy_pool, reload, runtime_config))
+        })
     };
 
+    // 启动代理网关, 监听的地址只在启动时读取一次
+    let gateway_config = init_config(config_file.as_ref())?.gateway;
+    if gateway_config.http.is_some() || gateway_config.socks.is_some() {
+        let proxy_pool = proxy_pool.clone();
+        let runtime_config = runtime_config.clone();
+        thread::spawn(move || {
+            if let Err(e) = crate::gateway::run(proxy_pool, gateway_config, runtime_config) {
+                error!("代理网关启动失败: {}", e);
+            }
+        });
+    }
+
     thread::spawn(move || loop {
         // 读取配置, 这个地方先解构一下
         // 因为 checker_config 是要被 Arc wrap 以后传来传去的
         // 而 spider_config 由于用到的相关变量都是 Copy 的, 所以直接传就行了
         info!("正在读取配置");
         let Config {
-            password: new_password,
+            password,
+            judge,
+            score,
+            lease,
+            report,
+            session,
+            geoip: geoip_config,
             checker: checker_config,
+            gateway: _,
             spider: spider_config,
         }: Config = init_config(config_file.as_ref()).expect("解析配置文件错误");
 
-        *password.write().unwrap() = Some(new_password);
+        set_api_password(&password);
+        *runtime_config.write().unwrap() = RuntimeConfig {
+            password: Some(password),
+            judge,
+            score,
+            lease,
+            report,
+            session,
+            targets: checker_config
+                .targets
+                .iter()
+                .map(|target| target.name.clone())
+                .collect(),
+        };
+        match GeoIp::open(&geoip_config) {
+            Ok(geoip) => proxy_pool.set_geoip(geoip),
+            Err(e) => error!("{}", e),
+        }
 
         // 用 Arc wrap 一下 checker_config
         // TODO: 此处的 Arc 感觉可以避免, CheckerConfig 内部可以试试不继续嵌套 struct 了
@@ -184,15 +314,15 @@ pub fn run() -> Result<(), Error> {
             let proxy_pool = proxy_pool.clone();
             let reload = reload.clone();
             thread::spawn(move || {
+                // 所有验证都在这个运行时中并发执行
+                let runtime = Runtime::new().expect("无法创建 tokio 运行时");
                 // 5s后开始验证, 免得验证时代理池是空的
                 sleep(Duration::from_secs(5));
                 loop {
-                    checker_thread(proxy_pool.clone(), checker_config.clone());
+                    runtime.block_on(checker_thread(proxy_pool.clone(), checker_config.clone()));
                     // TODO: 这个"备份"也单独开一个线程?
                     info!("写入到磁盘");
-                    let data = serde_json::to_string_pretty(&proxy_pool).expect("无法序列化");
-                    let mut file = File::create(&*DATA_PATH).expect("无法创建文件");
-                    file.write_all(data.as_bytes()).expect("无法写入");
+                    save_proxy_pool(&proxy_pool).expect("无法写入");
 
                     info!("等待{}秒再次验证...", checker_config.interval);
                     for _ in 0..checker_config.interval {
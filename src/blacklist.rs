@@ -0,0 +1,123 @@
+use crate::spider::proxy::Proxy;
+use failure::Error;
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// 封禁规则
+#[derive(Debug, Clone, Copy)]
+pub enum BanRule {
+    /// 单个 IP
+    Ip(IpAddr),
+    /// IP 段
+    Network(IpNetwork),
+    /// 端口
+    Port(u16),
+}
+
+impl BanRule {
+    /// 代理是否符合这条规则
+    pub fn matches(&self, proxy: &Proxy) -> bool {
+        match self {
+            BanRule::Ip(ip) => proxy.ip() == *ip,
+            BanRule::Network(network) => network.contains(proxy.ip()),
+            BanRule::Port(port) => proxy.port() == *port,
+        }
+    }
+}
+
+/// 黑名单, 被封禁的代理不会再被加入代理池
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Blacklist {
+    /// 被封禁的 IP
+    #[serde(default)]
+    ips: BTreeSet<IpAddr>,
+    /// 被封禁的 IP 段
+    #[serde(default)]
+    networks: BTreeSet<IpNetwork>,
+    /// 被封禁的端口
+    #[serde(default)]
+    ports: BTreeSet<u16>,
+    /// 保存的位置, 为 None 时不保存
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Blacklist {
+    /// 从文件读取黑名单, 文件不存在时返回空的黑名单
+    /// 之后每次修改都会保存到这个文件
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut blacklist = if path.exists() {
+            serde_json::from_reader(File::open(path)?)?
+        } else {
+            Blacklist::default()
+        };
+        blacklist.path = Some(path.to_owned());
+        Ok(blacklist)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let data = serde_json::to_string_pretty(self)?;
+            File::create(path)?.write_all(data.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// 代理是否被封禁
+    pub fn contains(&self, proxy: &Proxy) -> bool {
+        self.ips.contains(&proxy.ip())
+            || self.ports.contains(&proxy.port())
+            || self
+                .networks
+                .iter()
+                .any(|network| network.contains(proxy.ip()))
+    }
+
+    /// 添加一条规则, 返回规则之前是否不存在
+    /// 保存失败时撤销这次修改, 保持内存和文件一致
+    pub fn ban(&mut self, rule: BanRule) -> Result<bool, Error> {
+        let added = self.insert(rule);
+        if added {
+            if let Err(e) = self.save() {
+                self.remove(rule);
+                return Err(e);
+            }
+        }
+        Ok(added)
+    }
+
+    /// 删除一条规则, 返回规则之前是否存在
+    /// 保存失败时撤销这次修改, 保持内存和文件一致
+    pub fn unban(&mut self, rule: BanRule) -> Result<bool, Error> {
+        let removed = self.remove(rule);
+        if removed {
+            if let Err(e) = self.save() {
+                self.insert(rule);
+                return Err(e);
+            }
+        }
+        Ok(removed)
+    }
+
+    fn insert(&mut self, rule: BanRule) -> bool {
+        match rule {
+            BanRule::Ip(ip) => self.ips.insert(ip),
+            BanRule::Network(network) => self.networks.insert(network),
+            BanRule::Port(port) => self.ports.insert(port),
+        }
+    }
+
+    fn remove(&mut self, rule: BanRule) -> bool {
+        match rule {
+            BanRule::Ip(ip) => self.ips.remove(&ip),
+            BanRule::Network(network) => self.networks.remove(&network),
+            BanRule::Port(port) => self.ports.remove(&port),
+        }
+    }
+}
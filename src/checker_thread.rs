@@ -1,112 +1,247 @@
 use crate::config::CheckerConfig;
-use crate::proxy_pool::AProxyPool;
-use crate::spider::utils::check_proxy;
-use log::info;
+use crate::proxy_pool::{unix_now, AProxyPool};
+use crate::spider::proxy::Proxy;
+use crate::spider::utils::{check_anonymity, check_proxy, check_url, get_real_ip};
+use futures::future::join_all;
+use log::{error, info};
 use std::sync::Arc;
-use threadpool::ThreadPool;
+use tokio::sync::Semaphore;
 
-// 检查稳定代理
-fn check_stable(proxy_pool: AProxyPool, checker_config: Arc<CheckerConfig>) {
-    // TODO: 避免 clone ?
-    // 为了避免验证代理时造成阻塞, 先 clone 一遍
-    let stable = proxy_pool.get_stable().clone();
-    let pool = ThreadPool::new(checker_config.max_workers);
-
-    // 反正是 clone 的, consume 掉也无所谓
-    for proxy in stable {
-        let proxy_pool = proxy_pool.clone();
-        // TODO: 避免 clone ?
-        let checker_config = checker_config.clone();
-
-        pool.execute(move || {
-            if check_proxy(&proxy, &checker_config) {
-                info!("验证成功: {}:{}", proxy.ip(), proxy.port());
-                proxy_pool.clone().inc_success_cnt(&proxy);
-            } else {
-                info!("验证失败: {}:{}", proxy.ip(), proxy.port());
-                proxy_pool.clone().inc_failed_cnt(&proxy);
+// 通过 judge 验证代理真实的匿名程度, 并覆盖爬取到的值
+async fn verify_anonymity(
+    proxy_pool: &AProxyPool,
+    proxy: &Proxy,
+    checker_config: &CheckerConfig,
+    real_ip: &Option<Arc<String>>,
+) {
+    let (judge_url, real_ip) = match (&checker_config.judge_url, real_ip) {
+        (Some(judge_url), Some(real_ip)) => (judge_url, real_ip),
+        _ => return,
+    };
+    match check_anonymity(proxy, judge_url, real_ip, checker_config.timeout).await {
+        Ok((anonymity, exit_ip)) => {
+            if let Some(exit_ip) = exit_ip {
+                if proxy_pool.clone().set_exit_ip(proxy, exit_ip) {
+                    info!("出口 IP: {}, {}", proxy, exit_ip);
+                }
             }
-
-            let (stability, _, fail_times) = proxy_pool.clone().get_info(&proxy);
-
-            // 稳定率过低
-            if stability < checker_config.stability.level_down {
-                info!("稳定率:{:.2}, 降级为不稳定", stability);
-                proxy_pool.clone().move_to_unstable(&proxy);
-            // 连续失败次数过多
-            } else if fail_times >= checker_config.fail_times.level_down {
+            if anonymity != proxy.anonymity() {
                 info!(
-                    "连续验证失败{}次, 降级为不稳定",
-                    checker_config.fail_times.level_down
+                    "匿名程度: {}, {:?} -> {:?}",
+                    proxy,
+                    proxy.anonymity(),
+                    anonymity
                 );
-                proxy_pool.clone().move_to_unstable(&proxy);
+                proxy_pool.clone().set_anonymity(proxy, anonymity);
             }
-        });
+        }
+        Err(e) => info!("匿名程度验证失败: {}, {}", proxy, e),
     }
+}
 
-    // 等待所有线程结束
-    pool.join();
+// 验证额外的目标, 记录代理对每个目标是否可用
+async fn check_targets(proxy_pool: &AProxyPool, proxy: &Proxy, checker_config: &CheckerConfig) {
+    for target in &checker_config.targets {
+        let success = check_url(proxy, &target.url, &target.expect, checker_config.timeout)
+            .await
+            .is_some();
+        info!(
+            "目标 {}: {}, {}",
+            target.name,
+            proxy,
+            if success { "成功" } else { "失败" }
+        );
+        proxy_pool
+            .clone()
+            .record_target(proxy, &target.name, success);
+    }
+}
+
+// 验证一次代理并记录结果, 返回这次结果是否和上次不同
+// 代理已经被删除时返回 None
+async fn check_and_record(
+    proxy_pool: &AProxyPool,
+    proxy: &Proxy,
+    checker_config: &CheckerConfig,
+    real_ip: &Option<Arc<String>>,
+) -> Option<bool> {
+    let (_, check_cnt, fail_times) = proxy_pool.clone().get_info(proxy)?;
+    // 还没验证过的代理没有上次结果, 也算作变化
+    let last_success = if check_cnt == 0 {
+        None
+    } else {
+        Some(fail_times == 0)
+    };
+
+    let success = if let Some(latency) = check_proxy(proxy, checker_config).await {
+        info!("验证成功: {}, 延迟: {}ms", proxy, latency);
+        proxy_pool.clone().inc_success_cnt(proxy);
+        proxy_pool.clone().record_latency(proxy, latency);
+        verify_anonymity(proxy_pool, proxy, checker_config, real_ip).await;
+        check_targets(proxy_pool, proxy, checker_config).await;
+        true
+    } else {
+        info!("验证失败: {}", proxy);
+        proxy_pool.clone().inc_failed_cnt(proxy);
+        false
+    };
+    Some(last_success != Some(success))
+}
+
+// 安排代理的下次验证
+fn reschedule(
+    proxy_pool: &AProxyPool,
+    proxy: &Proxy,
+    checker_config: &CheckerConfig,
+    changed: bool,
+) {
+    let min_interval = checker_config.interval;
+    // 没有设置 max_interval 的话就和以前一样, 每轮都验证所有代理
+    let max_interval = checker_config.max_interval.unwrap_or(min_interval);
+    proxy_pool
+        .clone()
+        .reschedule(proxy, changed, min_interval, max_interval);
+}
+
+// 检查稳定代理
+async fn check_stable(
+    proxy_pool: AProxyPool,
+    checker_config: Arc<CheckerConfig>,
+    real_ip: Option<Arc<String>>,
+    proxy: Proxy,
+) {
+    let mut changed = match check_and_record(&proxy_pool, &proxy, &checker_config, &real_ip).await {
+        Some(changed) => changed,
+        None => return,
+    };
+
+    // 验证期间代理可能已经被删除了
+    let (stability, _, fail_times) = match proxy_pool.clone().get_info(&proxy) {
+        Some(info) => info,
+        None => return,
+    };
+
+    // 稳定率过低
+    if stability < checker_config.stability.level_down {
+        info!("稳定率:{:.2}, 降级为不稳定", stability);
+        proxy_pool.clone().move_to_unstable(&proxy);
+        changed = true;
+    // 连续失败次数过多
+    } else if fail_times >= checker_config.fail_times.level_down {
+        info!(
+            "连续验证失败{}次, 降级为不稳定",
+            checker_config.fail_times.level_down
+        );
+        proxy_pool.clone().move_to_unstable(&proxy);
+        changed = true;
+    }
+
+    reschedule(&proxy_pool, &proxy, &checker_config, changed);
 }
 
 // TODO: 这两个函数大体框架一致, 是否能简化一下?
 // 检查不稳定代理
-fn check_unstable(proxy_pool: AProxyPool, checker_config: Arc<CheckerConfig>) {
-    let unstable = proxy_pool.get_unstable().clone();
-    let pool = ThreadPool::new(checker_config.max_workers);
-
-    for proxy in unstable {
-        let proxy_pool = proxy_pool.clone();
-        let checker_config = checker_config.clone();
+async fn check_unstable(
+    proxy_pool: AProxyPool,
+    checker_config: Arc<CheckerConfig>,
+    real_ip: Option<Arc<String>>,
+    proxy: Proxy,
+) {
+    let mut changed = match check_and_record(&proxy_pool, &proxy, &checker_config, &real_ip).await {
+        Some(changed) => changed,
+        None => return,
+    };
 
-        pool.execute(move || {
-            if check_proxy(&proxy, &checker_config) {
-                info!("验证成功: {}:{}", proxy.ip(), proxy.port());
-                proxy_pool.clone().inc_success_cnt(&proxy);
-            } else {
-                info!("验证失败: {}:{}", proxy.ip(), proxy.port());
-                proxy_pool.clone().inc_failed_cnt(&proxy);
-            }
+    // 验证期间代理可能已经被删除了
+    let (stability, check_cnt, fail_times) = match proxy_pool.clone().get_info(&proxy) {
+        Some(info) => info,
+        None => return,
+    };
 
-            let (stability, check_cnt, fail_times) = proxy_pool.clone().get_info(&proxy);
-
-            // 检测次数 & 稳定率达标
-            if check_cnt >= checker_config.min_cnt_level_up
-                && stability >= checker_config.stability.level_up
-            {
-                info!("稳定率:{:.2}, 标记为稳定", stability);
-                proxy_pool.clone().move_to_stable(&proxy);
-            // 稳定率过低
-            } else if check_cnt >= checker_config.min_cnt_remove
-                && stability < checker_config.stability.remove
-            {
-                info!("稳定率:{:.2}, 从列表中移除", stability);
-                proxy_pool.clone().remove_unstable(&proxy);
-            // 连续失败次数过多
-            } else if fail_times >= checker_config.fail_times.remove {
-                info!(
-                    "连续验证失败{}次, 从列表中移除",
-                    checker_config.fail_times.remove
-                );
-                proxy_pool.clone().remove_unstable(&proxy);
-            // 烂代理扶不上墙
-            } else if check_cnt >= checker_config.max_cnt_remove {
-                info!(
-                    "{}次验证后仍不稳定, 从列表中移除",
-                    checker_config.max_cnt_remove
-                );
-                proxy_pool.clone().remove_unstable(&proxy);
-            }
-        });
+    // 检测次数 & 稳定率达标
+    if check_cnt >= checker_config.min_cnt_level_up
+        && stability >= checker_config.stability.level_up
+    {
+        info!("稳定率:{:.2}, 标记为稳定", stability);
+        proxy_pool.clone().move_to_stable(&proxy);
+        changed = true;
+    // 稳定率过低
+    } else if check_cnt >= checker_config.min_cnt_remove
+        && stability < checker_config.stability.remove
+    {
+        info!("稳定率:{:.2}, 从列表中移除", stability);
+        proxy_pool.clone().remove_unstable(&proxy);
+    // 连续失败次数过多
+    } else if fail_times >= checker_config.fail_times.remove {
+        info!(
+            "连续验证失败{}次, 从列表中移除",
+            checker_config.fail_times.remove
+        );
+        proxy_pool.clone().remove_unstable(&proxy);
+    // 烂代理扶不上墙
+    } else if check_cnt >= checker_config.max_cnt_remove {
+        info!(
+            "{}次验证后仍不稳定, 从列表中移除",
+            checker_config.max_cnt_remove
+        );
+        proxy_pool.clone().remove_unstable(&proxy);
     }
 
-    pool.join();
+    // 已经被移除的代理不会再被安排
+    reschedule(&proxy_pool, &proxy, &checker_config, changed);
 }
 
-/// 代理稳定性检查线程
-pub fn checker_thread(proxies: AProxyPool, checker_config: Arc<CheckerConfig>) {
+/// 代理稳定性检查, 需要在 tokio 运行时中执行
+pub async fn checker_thread(proxies: AProxyPool, checker_config: Arc<CheckerConfig>) {
     info!("代理验证开始");
-    check_stable(proxies.clone(), checker_config.clone());
-    // 节省一次 clone
-    check_unstable(proxies, checker_config);
+    // 每轮验证前先获取一次本机 IP, 用于判断代理是否透明
+    let real_ip = match &checker_config.judge_url {
+        Some(judge_url) => match get_real_ip(judge_url, checker_config.timeout).await {
+            Ok(ip) => Some(Arc::new(ip)),
+            Err(e) => {
+                error!("无法获取本机 IP, 跳过匿名程度验证: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    // 只验证到期的代理
+    let (stable, unstable) = proxies.clone().take_due(unix_now());
+    info!(
+        "待验证: 稳定 {} 个, 不稳定 {} 个",
+        stable.len(),
+        unstable.len()
+    );
+
+    // 每个代理一个任务, 用信号量限制同时进行的验证数量
+    let semaphore = Arc::new(Semaphore::new(checker_config.max_workers));
+    let mut tasks = Vec::with_capacity(stable.len() + unstable.len());
+    // 反正是 clone 的, consume 掉也无所谓
+    for (proxy, is_stable) in stable
+        .into_iter()
+        .map(|proxy| (proxy, true))
+        .chain(unstable.into_iter().map(|proxy| (proxy, false)))
+    {
+        let semaphore = semaphore.clone();
+        let proxy_pool = proxies.clone();
+        let checker_config = checker_config.clone();
+        let real_ip = real_ip.clone();
+
+        tasks.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await.expect("信号量已关闭");
+            if is_stable {
+                check_stable(proxy_pool, checker_config, real_ip, proxy).await;
+            } else {
+                check_unstable(proxy_pool, checker_config, real_ip, proxy).await;
+            }
+        }));
+    }
+
+    // 等待所有任务结束
+    for res in join_all(tasks).await {
+        if let Err(e) = res {
+            error!("验证任务崩溃: {}", e);
+        }
+    }
     info!("代理验证结束");
 }
@@ -1,4 +1,8 @@
-use serde::Deserialize;
+use crate::proxy_pool::Strategy;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 
 pub const DEFAULT_CONFIG: &'static str = include_str!("config.toml");
 
@@ -13,23 +17,214 @@ pub const DEFAULT_CONFIG: &'static str = include_str!("config.toml");
 pub struct Config {
     /// 管理密码
     pub password: String,
+    /// 是否开启 /judge 接口
+    #[serde(default)]
+    pub judge: bool,
+    /// 代理评分
+    #[serde(default)]
+    pub score: ScoreConfig,
+    /// 借出代理
+    #[serde(default)]
+    pub lease: LeaseConfig,
+    /// 客户端反馈
+    #[serde(default)]
+    pub report: ReportConfig,
+    /// 会话
+    #[serde(default)]
+    pub session: SessionConfig,
+    /// GeoIP 数据库
+    #[serde(default)]
+    pub geoip: GeoIpConfig,
+    /// 代理网关
+    #[serde(default)]
+    pub gateway: GatewayConfig,
     /// 验证线程
     pub checker: CheckerConfig,
     /// 爬虫线程
     pub spider: SpiderConfig,
 }
 
+/// 运行时可以重载的配置, 由 server 和代理网关共享
+/// 重载时整个替换, 读取时 clone 需要的部分, 不要长时间持有锁
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeConfig {
+    /// 管理密码, 读取配置前为 None
+    pub password: Option<String>,
+    /// 是否开启 /judge 接口
+    pub judge: bool,
+    /// 代理评分
+    pub score: ScoreConfig,
+    /// 借出代理
+    pub lease: LeaseConfig,
+    /// 客户端反馈
+    pub report: ReportConfig,
+    /// 会话
+    pub session: SessionConfig,
+    /// 验证目标的名称, 客户端只能反馈这些目标的结果
+    pub targets: Vec<String>,
+}
+
+pub type ARuntimeConfig = Arc<RwLock<RuntimeConfig>>;
+
+/// 代理评分配置
+/// 评分为各项得分(0 ~ 1)的乘积, 权重作为各项得分的指数, 设为 0 表示不考虑这一项
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScoreConfig {
+    /// /get 默认的取代理策略
+    pub strategy: Strategy,
+    /// 稳定率的权重
+    pub stability: f64,
+    /// 延迟的权重
+    pub latency: f64,
+    /// 距上次验证成功时间的权重
+    pub recency: f64,
+    /// 连续失败次数的权重
+    pub fail_times: f64,
+    /// 延迟为这个值(ms)时, 延迟得分为 0.5
+    pub latency_ref: f64,
+    /// 距上次验证成功这么多秒时, 时效得分为 0.5
+    pub recency_half_life: f64,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::Weighted,
+            stability: 1.0,
+            latency: 1.0,
+            recency: 1.0,
+            fail_times: 1.0,
+            latency_ref: 1000.0,
+            recency_half_life: 600.0,
+        }
+    }
+}
+
+/// 借出代理的配置
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LeaseConfig {
+    /// 同一个代理最多同时被借出几次
+    pub max_per_proxy: usize,
+    /// 默认的租期(secs)
+    pub default_ttl: u64,
+    /// 最长的租期(secs)
+    pub max_ttl: u64,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        Self {
+            max_per_proxy: 1,
+            default_ttl: 60,
+            max_ttl: 3600,
+        }
+    }
+}
+
+/// 客户端反馈的配置
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReportConfig {
+    /// 一次反馈成功计为几次验证成功
+    pub success_weight: u32,
+    /// 一次反馈失败计为几次验证失败
+    pub fail_weight: u32,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        Self {
+            success_weight: 1,
+            fail_weight: 2,
+        }
+    }
+}
+
+/// 会话的配置
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// 默认的过期时间(secs)
+    pub default_ttl: u64,
+    /// 最长的过期时间(secs)
+    pub max_ttl: u64,
+    /// 最多同时保留多少个会话
+    pub max_sessions: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            default_ttl: 600,
+            max_ttl: 86400,
+            max_sessions: 10000,
+        }
+    }
+}
+
+/// GeoIP 数据库的配置, 都不设置则不查询地理位置
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GeoIpConfig {
+    /// 国家/地区数据库(.mmdb), 如 GeoLite2-City 或 GeoLite2-Country
+    pub city: Option<String>,
+    /// 自治系统数据库(.mmdb), 如 GeoLite2-ASN
+    pub asn: Option<String>,
+}
+
+/// 代理网关的配置, 修改后需要重启才能生效
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GatewayConfig {
+    /// HTTP 代理监听的地址, 不设置则不开启
+    pub http: Option<SocketAddr>,
+    /// SOCKS5 代理监听的地址, 不设置则不开启
+    pub socks: Option<SocketAddr>,
+    /// 客户端认证的密码, 不设置则不需要认证
+    pub password: Option<String>,
+    /// 上游代理连接失败时最多换几个代理重试
+    pub retries: usize,
+    /// 连接上游代理并完成握手的超时时间(secs)
+    pub timeout: u64,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            http: None,
+            socks: None,
+            password: None,
+            retries: 3,
+            timeout: 10,
+        }
+    }
+}
+
 /// 验证线程配置
 #[derive(Debug, Deserialize)]
 pub struct CheckerConfig {
-    /// 验证线程数量
+    /// 最多同时进行的验证数量
     pub max_workers: usize,
-    /// 间隔时间(secs)
+    /// 间隔时间(secs), 也是单个代理的最小验证间隔
     pub interval: u64,
-    /// 进行HTTP验证的URL
+    /// 单个代理的最大验证间隔(secs), 不设置则每轮验证所有代理
+    pub max_interval: Option<u64>,
+    /// 进行HTTP验证的URL, SOCKS4/SOCKS5 也使用这个 URL
     pub url_http: String,
     /// 进行HTTPS验证的URL
     pub url_https: String,
+    /// 对 url_http 响应的要求
+    #[serde(default)]
+    pub expect_http: Expect,
+    /// 对 url_https 响应的要求
+    #[serde(default)]
+    pub expect_https: Expect,
+    /// 额外的验证目标
+    #[serde(default)]
+    pub targets: Vec<CheckTarget>,
+    /// 验证匿名程度和出口 IP 的 judge URL, 不设置则沿用爬取到的匿名程度
+    pub judge_url: Option<String>,
     /// 验证时允许的最大超时时间
     pub timeout: u64,
     /// 进入稳定列表所需最少验证次数
@@ -44,6 +239,49 @@ pub struct CheckerConfig {
     pub stability: Stability,
 }
 
+/// 额外的验证目标
+#[derive(Debug, Deserialize)]
+pub struct CheckTarget {
+    /// 目标名称, 用于 /get?target=
+    pub name: String,
+    /// 验证使用的 URL
+    pub url: String,
+    /// 对响应的要求
+    #[serde(default)]
+    pub expect: Expect,
+}
+
+/// 对验证响应的要求, 用于排除劫持页面/广告注入的代理
+#[derive(Debug, Default, Deserialize)]
+pub struct Expect {
+    /// 期望的状态码, 不设置则要求 2xx
+    pub status: Option<u16>,
+    /// 响应体必须包含的字符串
+    pub contains: Option<String>,
+    /// 响应体必须匹配的正则
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub regex: Option<Regex>,
+    /// 响应体的最大长度(bytes)
+    pub max_size: Option<u64>,
+    /// 必须存在的响应头
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+
+impl Expect {
+    /// 是否需要检查响应体, 需要的话就得用 GET 而不是 HEAD 了
+    pub fn need_body(&self) -> bool {
+        self.contains.is_some() || self.regex.is_some() || self.max_size.is_some()
+    }
+}
+
+// 在读取配置时就编译好正则, 免得每次验证都编译一遍
+fn deserialize_regex<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(d)?
+        .map(|s| Regex::new(&s).map_err(de::Error::custom))
+        .transpose()
+}
+
 /// 对连续失败次数的配置
 #[derive(Debug, Deserialize)]
 pub struct FailTimes {
@@ -88,7 +326,7 @@ pub struct CommonTable {
     pub xpath_line: String,
     /// 定位列的 xpath
     pub xpath_col: String,
-    /// IP, 端口, 匿名性, 类型 在列中的序号
+    /// IP, 端口, 匿名性, 协议 在列中的序号
     pub info_index: [usize; 4],
 }
 
@@ -107,6 +345,7 @@ pub struct CommonRegex {
     pub port: String,
     /// 匹配匿名程度
     pub anonymity: String,
-    /// 匹配HTTP/HTTPS
-    pub ssl_type: String,
+    /// 匹配协议(HTTP/HTTPS/SOCKS4/SOCKS5)
+    #[serde(alias = "ssl_type")]
+    pub protocol: String,
 }
@@ -0,0 +1,129 @@
+use crate::spider::proxy::{Protocol, Proxy};
+use failure::{format_err, Error};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// 导出的代理列表格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// 和 proxies.json 相同的 JSON
+    Json,
+    /// 每行一个 ip:port, 有认证信息时为 user:pass@ip:port
+    Txt,
+    /// ip,port,anonymity,protocol,username,password
+    Csv,
+    /// 浏览器使用的代理自动配置文件
+    Pac,
+    /// proxychains 配置文件的 [ProxyList] 部分
+    Proxychains,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "txt" | "text" => Ok(ExportFormat::Txt),
+            "csv" => Ok(ExportFormat::Csv),
+            "pac" => Ok(ExportFormat::Pac),
+            "proxychains" => Ok(ExportFormat::Proxychains),
+            _ => Err(format_err!("未知的格式: {}", s)),
+        }
+    }
+}
+
+impl ExportFormat {
+    /// 对应的 Content-Type
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Txt | ExportFormat::Proxychains => "text/plain; charset=utf-8",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Pac => "application/x-ns-proxy-autoconfig",
+        }
+    }
+
+    /// 按格式输出代理列表
+    pub fn render(self, proxies: &[Proxy]) -> String {
+        match self {
+            ExportFormat::Json => serde_json::to_string_pretty(proxies).unwrap(),
+            ExportFormat::Txt => render_txt(proxies),
+            ExportFormat::Csv => render_csv(proxies),
+            ExportFormat::Pac => render_pac(proxies),
+            ExportFormat::Proxychains => render_proxychains(proxies),
+        }
+    }
+}
+
+fn render_txt(proxies: &[Proxy]) -> String {
+    let mut ret = String::new();
+    for proxy in proxies {
+        if let Some(credentials) = proxy.credentials() {
+            write!(ret, "{}:{}@", credentials.username, credentials.password).unwrap();
+        }
+        // SocketAddr 的 Display 会给 IPv6 地址加上方括号
+        writeln!(ret, "{}", proxy.get_key()).unwrap();
+    }
+    ret
+}
+
+fn render_csv(proxies: &[Proxy]) -> String {
+    let mut ret = String::from("ip,port,anonymity,protocol,username,password\n");
+    for proxy in proxies {
+        let (username, password) = proxy
+            .credentials()
+            .map_or(("", ""), |c| (&c.username, &c.password));
+        writeln!(
+            ret,
+            "{},{},{},{},{},{}",
+            proxy.ip(),
+            proxy.port(),
+            proxy.anonymity().name(),
+            proxy.protocol().name(),
+            username,
+            password
+        )
+        .unwrap();
+    }
+    ret
+}
+
+// PAC 不支持代理认证, 浏览器会自己弹出认证框
+fn render_pac(proxies: &[Proxy]) -> String {
+    let mut list = proxies
+        .iter()
+        .map(|proxy| {
+            let kind = match proxy.protocol() {
+                // PAC 中的 HTTPS 表示和代理之间使用 TLS, 和我们的 HTTPS 代理不是一回事
+                Protocol::HTTP | Protocol::HTTPS => "PROXY",
+                Protocol::SOCKS4 => "SOCKS",
+                Protocol::SOCKS5 => "SOCKS5",
+            };
+            format!("{} {}", kind, proxy.get_key())
+        })
+        .collect::<Vec<_>>();
+    // 代理都不可用时直连
+    list.push("DIRECT".to_owned());
+    format!(
+        "function FindProxyForURL(url, host) {{\n    return \"{}\";\n}}\n",
+        list.join("; ")
+    )
+}
+
+fn render_proxychains(proxies: &[Proxy]) -> String {
+    let mut ret = String::from("[ProxyList]\n");
+    for proxy in proxies {
+        let kind = match proxy.protocol() {
+            Protocol::HTTP | Protocol::HTTPS => "http",
+            Protocol::SOCKS4 => "socks4",
+            Protocol::SOCKS5 => "socks5",
+        };
+        write!(ret, "{} {} {}", kind, proxy.ip(), proxy.port()).unwrap();
+        if let Some(credentials) = proxy.credentials() {
+            write!(ret, " {} {}", credentials.username, credentials.password).unwrap();
+        }
+        ret.push('\n');
+    }
+    ret
+}
@@ -0,0 +1,214 @@
+mod http;
+mod socks;
+mod upstream;
+
+use crate::config::{ARuntimeConfig, GatewayConfig};
+use crate::proxy_pool::{AProxyPool, Filter};
+use crate::spider::proxy::{AnonymityLevel, Protocol, Proxy};
+use failure::{format_err, Error};
+use futures::future::try_join;
+use log::debug;
+use rand::{seq::SliceRandom, thread_rng};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+/// 每次尝试最多随机取几次代理, 取到不能用或已经试过的代理时重新取, 用完后改为遍历
+const PICKS_PER_ATTEMPT: usize = 4;
+
+/// 连接目标的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// 建立到目标的隧道(CONNECT/SOCKS), 之后的数据原样转发
+    Tunnel,
+    /// 转发普通 HTTP 请求
+    /// HTTP 代理只连接到代理本身, 之后直接发送带完整 URL 的请求; SOCKS 代理仍然建立隧道
+    Forward,
+}
+
+/// 客户端通过用户名传递的选代理条件, 用 - 分隔, 如 elite-https, elite-session-abc
+/// session 之后的部分都是会话 ID, 所以会话 ID 可以包含 -(如 UUID), 但 session 必须放在最后
+#[derive(Debug, Default)]
+pub struct Hints {
+    /// 上游代理的协议
+    protocol: Option<Protocol>,
+    /// 上游代理的匿名程度
+    anonymity: Option<AnonymityLevel>,
+    /// 会话 ID, 同一个会话尽量使用同一个代理
+    session: Option<String>,
+}
+
+impl FromStr for Hints {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut hints = Hints::default();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let (token, tail) = match rest.find('-') {
+                Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+                None => (rest, ""),
+            };
+            rest = tail;
+            if token.is_empty() {
+                continue;
+            }
+            if token.eq_ignore_ascii_case("session") {
+                if rest.is_empty() {
+                    return Err(format_err!("session 后缺少会话 ID"));
+                }
+                hints.session = Some(rest.to_owned());
+                break;
+            } else if let Ok(protocol) = Protocol::parse_strict(token) {
+                hints.protocol = Some(protocol);
+            } else if let Ok(anonymity) = AnonymityLevel::parse_strict(token) {
+                hints.anonymity = Some(anonymity);
+            } else {
+                return Err(format_err!("无法识别的条件: {}", token));
+            }
+        }
+        Ok(hints)
+    }
+}
+
+impl Hints {
+    fn filter(&self) -> Filter {
+        Filter {
+            protocol: self.protocol,
+            anonymity: self.anonymity,
+            ..Filter::default()
+        }
+    }
+}
+
+/// 代理网关, 让 ppool 本身也能作为代理使用
+/// 每个请求(或隧道)都通过代理池中随机的一个代理转发
+pub struct Gateway {
+    proxy_pool: AProxyPool,
+    config: GatewayConfig,
+    /// 可以重载的配置, 只用到了会话的配置
+    runtime_config: ARuntimeConfig,
+}
+
+impl Gateway {
+    /// 验证客户端提供的用户名和密码, 返回用户名中的选代理条件
+    /// 没有设置密码时不要求认证, 但仍然可以通过用户名传递条件
+    pub fn authenticate(&self, credentials: Option<(&str, &str)>) -> Result<Hints, Error> {
+        match (&self.config.password, credentials) {
+            (Some(expected), Some((_, password))) if expected != password => {
+                Err(format_err!("密码错误"))
+            }
+            (Some(_), None) => Err(format_err!("需要认证")),
+            (_, Some((username, _))) => username.parse(),
+            (None, None) => Ok(Hints::default()),
+        }
+    }
+
+    /// 通过代理池中的代理连接 host:port, 连接或握手失败时换一个代理重试
+    /// 返回已完成握手的连接和使用的代理
+    pub async fn connect(
+        &self,
+        hints: &Hints,
+        host: &str,
+        port: u16,
+        mode: Mode,
+    ) -> Result<(TcpStream, Proxy), Error> {
+        // 会话之前使用的代理优先, 已被移出稳定列表(或不再符合条件)的话就换一个
+        let mut preferred = hints
+            .session
+            .as_ref()
+            .and_then(|session| self.proxy_pool.session_proxy(session))
+            .and_then(|key| self.proxy_pool.clone().get_matching(&key, &hints.filter()));
+
+        let timeout = Duration::from_secs(self.config.timeout);
+        let attempts = self.config.retries + 1;
+        // 只通过了 HTTP 验证的代理不一定支持 CONNECT
+        let usable = |proxy: &Proxy| mode != Mode::Tunnel || proxy.protocol() != Protocol::HTTP;
+        let mut tried = HashSet::new();
+        let mut picks = 0;
+        let mut fallback: Option<Vec<Proxy>> = None;
+        while tried.len() < attempts {
+            let proxy = if let Some(proxy) = preferred.take() {
+                proxy
+            // 每个连接都通过属性索引随机取, 不用遍历整个列表
+            } else if picks < attempts * PICKS_PER_ATTEMPT {
+                picks += 1;
+                match self.proxy_pool.clone().select_random(hints.filter(), false) {
+                    Some(proxy) => proxy,
+                    None => break,
+                }
+            // 随机取的次数用完了(比如符合条件的大多是 HTTP 代理), 退回遍历符合条件的代理
+            } else {
+                let fallback = fallback.get_or_insert_with(|| {
+                    let mut proxies = self.proxy_pool.clone().select(hints.filter());
+                    proxies.retain(|proxy| usable(proxy) && !tried.contains(&proxy.get_key()));
+                    proxies.shuffle(&mut thread_rng());
+                    proxies
+                });
+                match fallback.pop() {
+                    Some(proxy) => proxy,
+                    None => break,
+                }
+            };
+            // 已经试过的代理也不再试
+            if !usable(&proxy) || !tried.insert(proxy.get_key()) {
+                continue;
+            }
+            let result =
+                tokio::time::timeout(timeout, upstream::open(&proxy, host, port, mode)).await;
+            match result {
+                Ok(Ok(stream)) => {
+                    if let Some(session) = &hints.session {
+                        let config = self.runtime_config.read().unwrap().session.clone();
+                        self.proxy_pool.bind_session(
+                            session,
+                            &proxy,
+                            config.default_ttl,
+                            config.max_sessions,
+                        );
+                    }
+                    return Ok((stream, proxy));
+                }
+                Ok(Err(e)) => debug!("通过 {} 连接 {}:{} 失败: {}", proxy, host, port, e),
+                Err(_) => debug!("通过 {} 连接 {}:{} 超时", proxy, host, port),
+            }
+            // 让验证线程尽快重新验证这个代理
+            self.proxy_pool.clone().check_soon(&proxy);
+        }
+        Err(format_err!("没有可用的代理"))
+    }
+}
+
+/// 启动代理网关, 会阻塞当前线程
+pub fn run(
+    proxy_pool: AProxyPool,
+    config: GatewayConfig,
+    runtime_config: ARuntimeConfig,
+) -> Result<(), Error> {
+    let runtime = Runtime::new()?;
+    let (http, socks) = (config.http, config.socks);
+    let gateway = Arc::new(Gateway {
+        proxy_pool,
+        config,
+        runtime_config,
+    });
+    runtime.block_on(async move {
+        let http = async {
+            match http {
+                Some(addr) => http::serve(gateway.clone(), addr).await,
+                None => Ok(()),
+            }
+        };
+        let socks = async {
+            match socks {
+                Some(addr) => socks::serve(gateway.clone(), addr).await,
+                None => Ok(()),
+            }
+        };
+        try_join(http, socks).await?;
+        Ok(())
+    })
+}
@@ -0,0 +1,217 @@
+use super::{Gateway, Hints, Mode};
+use crate::handshake::{authority, proxy_authorization};
+use crate::spider::proxy::Protocol;
+use failure::{format_err, Error};
+use log::{debug, info, warn};
+use reqwest::Url;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// 请求头的最大长度
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// 逐跳(hop-by-hop)的请求头, 不转发给上游
+const HOP_HEADERS: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Upgrade",
+];
+
+/// 监听 HTTP 代理请求
+pub async fn serve(gateway: Arc<Gateway>, addr: SocketAddr) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("HTTP 代理网关监听于 {}", addr);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("HTTP 代理网关 accept 失败: {}", e);
+                continue;
+            }
+        };
+        let gateway = gateway.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&gateway, stream).await {
+                debug!("处理 {} 的请求失败: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// 读取请求头, 返回请求头的长度
+/// 多读到的数据(请求体或隧道中的数据)留在 buf 中, 之后转发给上游
+async fn read_head(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<usize, Error> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(pos + 4);
+        }
+        if buf.len() >= MAX_HEAD_SIZE {
+            return Err(format_err!("请求头过长"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(format_err!("连接已关闭"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// 返回一个简单的错误响应
+async fn respond(stream: &mut TcpStream, status: &str, message: &str) -> Result<(), Error> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        message.len(),
+        message
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// 从 Proxy-Authorization 中取出用户名和密码, 只支持 Basic
+fn parse_credentials(value: &[u8]) -> Option<(String, String)> {
+    let value = std::str::from_utf8(value).ok()?;
+    let token = value.trim().strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(token.trim()).ok()?).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    let username = parts.next()?.to_owned();
+    let password = parts.next()?.to_owned();
+    Some((username, password))
+}
+
+/// 验证客户端, 失败时返回 407
+async fn authenticate(
+    gateway: &Gateway,
+    client: &mut TcpStream,
+    request: &httparse::Request<'_, '_>,
+) -> Result<Option<Hints>, Error> {
+    let credentials = request
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Proxy-Authorization"))
+        .and_then(|header| parse_credentials(header.value));
+    let credentials = credentials
+        .as_ref()
+        .map(|(username, password)| (username.as_str(), password.as_str()));
+    match gateway.authenticate(credentials) {
+        Ok(hints) => Ok(Some(hints)),
+        Err(e) => {
+            let message = e.to_string();
+            let response = format!(
+                "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"ppool\"\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                message.len(),
+                message
+            );
+            client.write_all(response.as_bytes()).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// CONNECT host:port
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    let pos = authority.rfind(':')?;
+    let host = authority[..pos]
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = authority[pos + 1..].parse().ok()?;
+    Some((host.to_owned(), port))
+}
+
+// 每个连接只处理一个请求, 转发时加上 Connection: close
+// 这样下一个请求会重新连接, 也就会换一个代理
+async fn handle(gateway: &Gateway, mut client: TcpStream) -> Result<(), Error> {
+    let mut buf = vec![];
+    let len = read_head(&mut client, &mut buf).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    if request.parse(&buf[..len]).is_err() {
+        return respond(&mut client, "400 Bad Request", "无法解析请求").await;
+    }
+    let method = request.method.unwrap_or_default();
+    let path = request.path.unwrap_or_default();
+    // 用户名可以携带选代理的条件, 和 SOCKS5 相同
+    let hints = match authenticate(gateway, &mut client, &request).await? {
+        Some(hints) => hints,
+        None => return Ok(()),
+    };
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = match parse_authority(path) {
+            Some(target) => target,
+            None => return respond(&mut client, "400 Bad Request", "无效的目标地址").await,
+        };
+        let mut upstream = match gateway.connect(&hints, &host, port, Mode::Tunnel).await {
+            Ok((upstream, _)) => upstream,
+            Err(e) => return respond(&mut client, "502 Bad Gateway", &e.to_string()).await,
+        };
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        // 客户端可能没等响应就发出了 TLS 握手
+        upstream.write_all(&buf[len..]).await?;
+        copy_bidirectional(&mut client, &mut upstream).await?;
+        return Ok(());
+    }
+
+    // 普通请求的路径必须是完整的 URL
+    let url = match Url::parse(path) {
+        Ok(url) if url.scheme() == "http" && url.host_str().is_some() => url,
+        _ => return respond(&mut client, "400 Bad Request", "只支持 http:// 的完整 URL").await,
+    };
+    let host = url.host_str().unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let (mut upstream, proxy) = match gateway.connect(&hints, host, port, Mode::Forward).await {
+        Ok(conn) => conn,
+        Err(e) => return respond(&mut client, "502 Bad Gateway", &e.to_string()).await,
+    };
+
+    // HTTP 代理发送完整的 URL, SOCKS 隧道则直接发给目标服务器
+    let forward = matches!(proxy.protocol(), Protocol::HTTP | Protocol::HTTPS);
+    let target = if forward {
+        path.to_owned()
+    } else {
+        match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        }
+    };
+    let mut head = format!("{} {} HTTP/1.1\r\n", method, target).into_bytes();
+    let mut has_host = false;
+    for header in request.headers.iter() {
+        if HOP_HEADERS
+            .iter()
+            .any(|name| header.name.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        has_host |= header.name.eq_ignore_ascii_case("Host");
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+    if !has_host {
+        head.extend_from_slice(format!("Host: {}\r\n", authority(host, port)).as_bytes());
+    }
+    if forward {
+        if let Some(authorization) = proxy_authorization(&proxy) {
+            head.extend_from_slice(
+                format!("Proxy-Authorization: {}\r\n", authorization).as_bytes(),
+            );
+        }
+    }
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+
+    upstream.write_all(&head).await?;
+    upstream.write_all(&buf[len..]).await?;
+    copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use super::{Gateway, Hints, Mode};
+use failure::{format_err, Error};
+use log::{debug, info, warn};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// 无需认证
+const NO_AUTH: u8 = 0;
+/// 用户名/密码认证(RFC 1929)
+const USER_PASS: u8 = 2;
+/// 没有可接受的认证方式
+const NO_ACCEPTABLE: u8 = 0xFF;
+
+/// 监听 SOCKS5 请求
+pub async fn serve(gateway: Arc<Gateway>, addr: SocketAddr) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("SOCKS5 代理网关监听于 {}", addr);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("SOCKS5 代理网关 accept 失败: {}", e);
+                continue;
+            }
+        };
+        let gateway = gateway.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&gateway, stream).await {
+                debug!("处理 {} 的请求失败: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// 读取一个长度前缀的字符串
+async fn read_string(stream: &mut TcpStream) -> Result<String, Error> {
+    let len = stream.read_u8().await? as usize;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// 协商认证方式, 返回用户名中的选代理条件
+async fn negotiate(gateway: &Gateway, stream: &mut TcpStream) -> Result<Hints, Error> {
+    // VER=5, NMETHODS, METHODS
+    if stream.read_u8().await? != 5 {
+        return Err(format_err!("不是 SOCKS5 请求"));
+    }
+    let len = stream.read_u8().await? as usize;
+    let mut methods = vec![0u8; len];
+    stream.read_exact(&mut methods).await?;
+
+    // 设置了密码时必须认证, 否则客户端愿意的话也可以通过用户名传递条件
+    let method = if methods.contains(&USER_PASS) {
+        USER_PASS
+    } else if methods.contains(&NO_AUTH) && gateway.config.password.is_none() {
+        NO_AUTH
+    } else {
+        NO_ACCEPTABLE
+    };
+    stream.write_all(&[5, method]).await?;
+    match method {
+        NO_AUTH => return Ok(Hints::default()),
+        NO_ACCEPTABLE => return Err(format_err!("没有可接受的认证方式")),
+        _ => (),
+    }
+
+    // VER=1, ULEN, UNAME, PLEN, PASSWD
+    if stream.read_u8().await? != 1 {
+        return Err(format_err!("未知的认证协议版本"));
+    }
+    let username = read_string(stream).await?;
+    let password = read_string(stream).await?;
+    match gateway.authenticate(Some((&username, &password))) {
+        Ok(hints) => {
+            stream.write_all(&[1, 0]).await?;
+            Ok(hints)
+        }
+        Err(e) => {
+            stream.write_all(&[1, 1]).await?;
+            Err(e)
+        }
+    }
+}
+
+/// 读取 CONNECT 请求的目标地址
+async fn read_target(stream: &mut TcpStream) -> Result<(String, u16), Error> {
+    // VER=5, CMD, RSV, ATYP, DST.ADDR, DST.PORT
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    if request[1] != 1 {
+        // REP=7: 不支持的命令
+        stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        return Err(format_err!("不支持的命令: {}", request[1]));
+    }
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        3 => read_string(stream).await?,
+        atyp => {
+            // REP=8: 不支持的地址类型
+            stream.write_all(&[5, 8, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            return Err(format_err!("未知的地址类型: {}", atyp));
+        }
+    };
+    let port = stream.read_u16().await?;
+    Ok((host, port))
+}
+
+async fn handle(gateway: &Gateway, mut client: TcpStream) -> Result<(), Error> {
+    let hints = negotiate(gateway, &mut client).await?;
+    let (host, port) = read_target(&mut client).await?;
+    let mut upstream = match gateway.connect(&hints, &host, port, Mode::Tunnel).await {
+        Ok((upstream, _)) => upstream,
+        Err(e) => {
+            // REP=1: 一般性失败
+            client.write_all(&[5, 1, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            return Err(e);
+        }
+    };
+    // 上游代理的 BND.ADDR 对客户端没有意义, 统一返回 0.0.0.0:0
+    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
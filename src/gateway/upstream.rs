@@ -0,0 +1,17 @@
+use super::Mode;
+use crate::handshake::{http_connect, socks4_connect, socks5_connect};
+use crate::spider::proxy::{Protocol, Proxy};
+use failure::Error;
+use tokio::net::TcpStream;
+
+/// 连接到上游代理并完成握手
+pub async fn open(proxy: &Proxy, host: &str, port: u16, mode: Mode) -> Result<TcpStream, Error> {
+    let mut stream = TcpStream::connect(proxy.get_key()).await?;
+    match proxy.protocol() {
+        Protocol::HTTP | Protocol::HTTPS if mode == Mode::Forward => (),
+        Protocol::HTTP | Protocol::HTTPS => http_connect(&mut stream, proxy, host, port).await?,
+        Protocol::SOCKS4 => socks4_connect(&mut stream, proxy, host, port).await?,
+        Protocol::SOCKS5 => socks5_connect(&mut stream, proxy, host, port).await?,
+    }
+    Ok(stream)
+}
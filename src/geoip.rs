@@ -0,0 +1,151 @@
+use crate::config::GeoIpConfig;
+use failure::{format_err, Error};
+use maxminddb::{geoip2, Reader};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// 代理的地理位置信息
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GeoInfo {
+    /// 国家/地区代码(ISO 3166-1), 如 CN
+    #[serde(default)]
+    pub country: Option<String>,
+    /// 省/州, 优先使用中文名称
+    #[serde(default)]
+    pub region: Option<String>,
+    /// 自治系统号
+    #[serde(default)]
+    pub asn: Option<u32>,
+    /// 自治系统所属的组织
+    #[serde(default)]
+    pub as_org: Option<String>,
+}
+
+/// 离线的 GeoIP 数据库, MaxMind DB(.mmdb) 格式
+/// 如 GeoLite2-City/GeoLite2-Country 和 GeoLite2-ASN, IP2Location 也提供这个格式的数据库
+#[derive(Debug)]
+pub struct GeoIp {
+    /// 国家/地区和省/州
+    city: Option<Reader<Vec<u8>>>,
+    /// 自治系统
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// 打开配置中的数据库, 一个都没有配置时返回 None
+    pub fn open(config: &GeoIpConfig) -> Result<Option<Self>, Error> {
+        let open = |path: &Option<String>| {
+            path.as_ref()
+                .map(|path| {
+                    Reader::open_readfile(path)
+                        .map_err(|e| format_err!("无法打开 GeoIP 数据库 {}: {}", path, e))
+                })
+                .transpose()
+        };
+        let geoip = Self {
+            city: open(&config.city)?,
+            asn: open(&config.asn)?,
+        };
+        if geoip.city.is_none() && geoip.asn.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(geoip))
+        }
+    }
+
+    /// 查询 IP 的地理位置, 数据库中都没有这个 IP 时返回 None
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoInfo> {
+        let mut info = GeoInfo::default();
+        // Country 数据库的记录是 City 的子集, 可以用同一个结构读取
+        if let Some(city) = self
+            .city
+            .as_ref()
+            .and_then(|reader| reader.lookup::<geoip2::City>(ip).ok())
+        {
+            info.country = city
+                .country
+                .and_then(|country| country.iso_code)
+                .map(str::to_owned);
+            info.region = city
+                .subdivisions
+                .and_then(|subdivisions| subdivisions.into_iter().next())
+                .and_then(|subdivision| {
+                    let names = subdivision.names.unwrap_or_default();
+                    names
+                        .get("zh-CN")
+                        .or_else(|| names.get("en"))
+                        .copied()
+                        .or(subdivision.iso_code)
+                        .map(str::to_owned)
+                });
+        }
+        if let Some(asn) = self
+            .asn
+            .as_ref()
+            .and_then(|reader| reader.lookup::<geoip2::Asn>(ip).ok())
+        {
+            info.asn = asn.autonomous_system_number;
+            info.as_org = asn.autonomous_system_organization.map(str::to_owned);
+        }
+        if info == GeoInfo::default() {
+            None
+        } else {
+            Some(info)
+        }
+    }
+}
+
+/// 国家/地区的筛选条件
+/// CN 表示只要中国的代理, CN,HK 表示中国或香港, !CN 表示排除中国
+#[derive(Debug, Clone, PartialEq)]
+pub struct CountryFilter {
+    /// 是否为排除
+    exclude: bool,
+    /// 大写的国家/地区代码
+    codes: Vec<String>,
+}
+
+impl FromStr for CountryFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (exclude, s) = match s.strip_prefix('!') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let codes = s
+            .split(',')
+            .map(|code| code.trim().to_uppercase())
+            .collect::<Vec<_>>();
+        if codes
+            .iter()
+            .any(|code| code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()))
+        {
+            return Err(format_err!("无效的国家/地区代码: {}", s));
+        }
+        Ok(Self { exclude, codes })
+    }
+}
+
+impl CountryFilter {
+    /// 国家/地区是否符合条件, 未知的国家/地区只会被排除条件接受
+    pub fn matches(&self, country: Option<&str>) -> bool {
+        let found = country.map_or(false, |country| {
+            self.codes.iter().any(|code| code == country)
+        });
+        found != self.exclude
+    }
+}
+
+/// 解析自治系统号, 可以带上 AS 前缀
+pub fn parse_asn(s: &str) -> Result<u32, Error> {
+    let s = s.trim();
+    let number = match s.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("AS") => &s[2..],
+        _ => s,
+    };
+    number
+        .parse()
+        .map_err(|_| format_err!("无效的自治系统号: {}", s))
+}
@@ -0,0 +1,168 @@
+use crate::spider::proxy::Proxy;
+use failure::{format_err, Error};
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// 响应头的最大长度
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// host:port, IPv6 地址需要加上方括号
+pub fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// 代理认证的 Proxy-Authorization 头, 没有认证信息时返回 None
+pub fn proxy_authorization(proxy: &Proxy) -> Option<String> {
+    proxy.credentials().map(|credentials| {
+        let token = format!("{}:{}", credentials.username, credentials.password);
+        format!("Basic {}", base64::encode(token))
+    })
+}
+
+/// 通过 CONNECT 建立隧道
+pub async fn http_connect(
+    stream: &mut TcpStream,
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+) -> Result<(), Error> {
+    let mut request = format!(
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n",
+        authority(host, port)
+    );
+    if let Some(authorization) = proxy_authorization(proxy) {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // 逐字节读取响应头, 以免读到隧道中的数据
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(format_err!("响应头过长"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    response.parse(&head)?;
+    match response.code {
+        Some(200..=299) => Ok(()),
+        code => Err(format_err!("CONNECT 失败: {:?}", code)),
+    }
+}
+
+/// 完成 SOCKS4 握手
+/// SOCKS4 的目标地址只支持 IPv4, 在本地解析域名
+pub async fn socks4_connect(
+    stream: &mut TcpStream,
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+) -> Result<(), Error> {
+    let target = tokio::net::lookup_host((host, port))
+        .await?
+        .find_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .ok_or_else(|| format_err!("无法解析 {}", host))?;
+
+    // VN=4, CD=1(CONNECT), DSTPORT, DSTIP, USERID, NULL
+    // SOCKS4 没有密码, 只有 USERID
+    let mut request = vec![4, 1];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request.extend_from_slice(&target.ip().octets());
+    if let Some(credentials) = proxy.credentials() {
+        request.extend_from_slice(credentials.username.as_bytes());
+    }
+    request.push(0);
+    stream.write_all(&request).await?;
+
+    // VN=0, CD=90 表示请求被允许
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 90 {
+        return Err(format_err!("SOCKS4 请求被拒绝: {}", reply[1]));
+    }
+    Ok(())
+}
+
+/// 完成 SOCKS5 握手, 有认证信息时使用用户名/密码认证
+/// 域名交给代理解析
+pub async fn socks5_connect(
+    stream: &mut TcpStream,
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+) -> Result<(), Error> {
+    // VER=5, NMETHODS=1, METHOD: 0 为无需认证, 2 为用户名/密码
+    let method = if proxy.credentials().is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [5, method] {
+        return Err(format_err!("SOCKS5 不支持的认证方式: {}", reply[1]));
+    }
+
+    // RFC 1929: VER=1, ULEN, UNAME, PLEN, PASSWD
+    if let Some(credentials) = proxy.credentials() {
+        let username = credentials.username.as_bytes();
+        let password = credentials.password.as_bytes();
+        if username.len() > 255 || password.len() > 255 {
+            return Err(format_err!("SOCKS5 用户名或密码过长"));
+        }
+        let mut request = vec![1, username.len() as u8];
+        request.extend_from_slice(username);
+        request.push(password.len() as u8);
+        request.extend_from_slice(password);
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(format_err!("SOCKS5 认证失败"));
+        }
+    }
+
+    // VER=5, CMD=1(CONNECT), RSV=0, ATYP, DST.ADDR, DST.PORT
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) if host.len() <= 255 => {
+            request.extend_from_slice(&[3, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        }
+        Err(_) => return Err(format_err!("域名过长: {}", host)),
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // VER, REP, RSV, ATYP, BND.ADDR, BND.PORT
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(format_err!("SOCKS5 请求被拒绝: {}", reply[1]));
+    }
+    // 跳过 BND.ADDR 和 BND.PORT
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        atyp => return Err(format_err!("SOCKS5 未知的地址类型: {}", atyp)),
+    };
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use crate::spider::proxy::{AnonymityLevel, Protocol, Proxy};
+use failure::{format_err, Error};
+use log::warn;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// 导入的代理列表格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// 代理对象或 ip:port 字符串组成的数组, 如 /get_all 的输出
+    Json,
+    /// 每行一个 ip:port, 可以带上 user:pass@ 和 socks5:// 之类的前缀
+    Txt,
+    /// ip,port[,匿名程度[,协议[,用户名,密码]]], 可以有表头, 和 /get_all?format=csv 的输出相同
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "txt" | "text" => Ok(Format::Txt),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format_err!("未知的格式: {}", s)),
+        }
+    }
+}
+
+impl Format {
+    /// 根据内容猜测格式
+    fn detect(data: &str) -> Self {
+        let data = data.trim_start();
+        if data.starts_with('[') {
+            Format::Json
+        } else if data.lines().next().map_or(false, |line| line.contains(',')) {
+            Format::Csv
+        } else {
+            Format::Txt
+        }
+    }
+}
+
+/// 解析一行 ip:port, 协议可以写成 URL 的 scheme
+fn parse_line(line: &str, protocol: &str) -> Result<Proxy, Error> {
+    let (protocol, addr) = match line.find("://") {
+        Some(pos) => (&line[..pos], &line[pos + 3..]),
+        None => (protocol, line),
+    };
+    // 写错的协议不能悄悄变成 HTTP
+    Protocol::parse_strict(protocol)?;
+    // 匿名程度留空, 默认为透明, 由验证线程通过 judge 确认
+    Proxy::new(addr.trim_end_matches('/'), "", "", protocol)
+}
+
+/// 解析一行 CSV
+/// 匿名程度和协议严格解析, 写错时这一行算作无法解析, 而不是悄悄变成透明/HTTP
+fn parse_csv(line: &str, protocol: &str) -> Result<Proxy, Error> {
+    let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+    let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+    let protocol = if field(3).is_empty() {
+        protocol
+    } else {
+        Protocol::parse_strict(field(3))?;
+        field(3)
+    };
+    let ip = if field(4).is_empty() {
+        field(0).to_owned()
+    } else {
+        format!("{}:{}@{}", field(4), field(5), field(0))
+    };
+    let mut proxy = Proxy::new(&ip, field(1), "", protocol)?;
+    if !field(2).is_empty() {
+        proxy.set_anonymity(AnonymityLevel::parse_strict(field(2))?);
+    }
+    Ok(proxy)
+}
+
+/// 解析代理列表, 返回 (代理, 无法解析的条数)
+/// format 为 None 时自动检测格式, 没有写明协议的代理使用 protocol
+pub fn parse_proxies(
+    data: &str,
+    format: Option<Format>,
+    protocol: &str,
+) -> Result<(Vec<Proxy>, usize), Error> {
+    let mut proxies = vec![];
+    let mut invalid = 0;
+    // 原始内容可能带有认证信息, 日志中只记录行号(JSON 为数组下标)
+    let mut push = |result: Result<Proxy, Error>, index: usize| match result {
+        Ok(proxy) => proxies.push(proxy),
+        Err(e) => {
+            warn!("无法解析第 {} 个代理: {}", index + 1, e);
+            invalid += 1;
+        }
+    };
+
+    match format.unwrap_or_else(|| Format::detect(data)) {
+        Format::Json => {
+            let items: Vec<Value> = serde_json::from_str(data)?;
+            for (index, item) in items.into_iter().enumerate() {
+                match item {
+                    Value::String(line) => push(parse_line(&line, protocol), index),
+                    item => push(serde_json::from_value(item).map_err(Error::from), index),
+                }
+            }
+        }
+        Format::Txt => {
+            for (index, line) in data.lines().map(str::trim).enumerate() {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                push(parse_line(line, protocol), index);
+            }
+        }
+        Format::Csv => {
+            let mut first = true;
+            for (index, line) in data.lines().map(str::trim).enumerate() {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let result = parse_csv(line, protocol);
+                // 第一行解析失败的话当作表头
+                if std::mem::replace(&mut first, false) && result.is_err() {
+                    continue;
+                }
+                push(result, index);
+            }
+        }
+    }
+    Ok((proxies, invalid))
+}
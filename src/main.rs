@@ -1,12 +1,18 @@
-#![feature(vec_remove_item, proc_macro_hygiene, decl_macro)]
+#![feature(proc_macro_hygiene, decl_macro)]
 #![feature(never_type)]
 
 #[macro_use]
 extern crate structopt;
 
 mod app;
+mod blacklist;
 mod checker_thread;
 mod config;
+mod export;
+mod gateway;
+mod geoip;
+mod handshake;
+mod import;
 mod options;
 mod proxy_pool;
 mod server;
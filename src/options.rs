@@ -12,4 +12,65 @@ pub struct Opt {
     /// 测试获取指定代理
     #[structopt(short = "t", long, value_name = "NAME")]
     pub test: Option<String>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// 从文件导入代理, 导入的代理会和爬取到的一样经过验证
+    /// 会直接修改缓存的代理列表, 请在 ppool 未运行时使用, 运行中请使用 POST /import
+    Import {
+        /// 代理列表文件
+        #[structopt(value_name = "FILE")]
+        file: String,
+
+        /// 文件格式, json/txt/csv, 不指定则自动检测
+        #[structopt(short, long)]
+        format: Option<String>,
+
+        /// 没有写明协议的代理使用的协议
+        #[structopt(short, long, default_value = "http")]
+        protocol: String,
+
+        /// 代理来源的标记
+        #[structopt(short, long, default_value = "import")]
+        source: String,
+    },
+
+    /// 按指定格式输出缓存中的稳定代理, 格式同 /get_all
+    Export {
+        /// 输出格式, json/txt/csv/pac/proxychains
+        #[structopt(short, long, default_value = "txt")]
+        format: String,
+
+        /// 协议
+        #[structopt(long)]
+        protocol: Option<String>,
+
+        /// 匿名程度
+        #[structopt(long)]
+        anonymity: Option<String>,
+
+        /// 最低稳定率
+        #[structopt(long)]
+        stability: Option<f32>,
+
+        /// 最大延迟/ms
+        #[structopt(long)]
+        max_latency: Option<u32>,
+
+        /// 能访问的验证目标
+        #[structopt(long)]
+        target: Option<String>,
+
+        /// 国家/地区代码, 如 CN, CN,HK, !CN
+        #[structopt(long)]
+        country: Option<String>,
+
+        /// 自治系统号, 如 AS4134
+        #[structopt(long)]
+        asn: Option<String>,
+    },
 }
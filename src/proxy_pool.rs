@@ -1,14 +1,39 @@
+use crate::blacklist::{BanRule, Blacklist};
+use crate::config::ScoreConfig;
+use crate::geoip::{parse_asn, CountryFilter, GeoInfo, GeoIp};
 use crate::spider::proxy::*;
+use failure::{format_err, Error};
 use owning_ref::RwLockReadGuardRef;
-use rand::{seq::SliceRandom, thread_rng};
+use rand::{seq::SliceRandom, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::SocketAddrV4;
-use std::sync::{Arc, RwLock};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub type AProxyPool = Arc<ProxyPool>;
-pub type ProxyInfo = RwLock<HashMap<SocketAddrV4, _ProxyInfo>>;
+pub type ProxyInfo = RwLock<HashMap<SocketAddr, _ProxyInfo>>;
 pub type ProxyList = RwLock<ProxyListInner>;
+/// 按下次验证时间排序的小根堆
+pub type Schedule = Mutex<BinaryHeap<Reverse<(u64, SocketAddr)>>>;
+pub type Leases = Mutex<LeaseTable>;
+/// 会话 ID -> (绑定的代理, 过期时间)
+pub type Sessions = Mutex<HashMap<String, (SocketAddr, u64)>>;
+
+/// 保留最近多少次验证的延迟, 用于计算百分位数
+const LATENCY_WINDOW: usize = 20;
+/// 延迟 EWMA 的平滑系数, 越大越看重最近的结果
+const LATENCY_ALPHA: f64 = 0.3;
+/// 还没有延迟数据的代理按这个延迟(ms)计算权重
+const UNKNOWN_LATENCY: f64 = 10_000.0;
+/// 延迟分桶的上界(ms), 之后还有一个没有上界的桶和一个延迟未知的桶
+const LATENCY_BOUNDS: [u32; 7] = [100, 200, 500, 1000, 2000, 5000, 10_000];
+/// 延迟未知的桶
+const UNKNOWN_LATENCY_BUCKET: u8 = LATENCY_BOUNDS.len() as u8 + 1;
+/// 按评分加权时拒绝采样的最大次数, 都被拒绝的话退回遍历
+const MAX_REJECTIONS: usize = 32;
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ProxyListInner {
@@ -16,6 +41,315 @@ pub struct ProxyListInner {
     unstable: Vec<Proxy>,
     /// 稳定代理
     stable: Vec<Proxy>,
+    /// 代理所在的列表(是否稳定)及其下标, 可以从列表中恢复, 不需要保存
+    #[serde(skip)]
+    index: HashMap<SocketAddr, (bool, usize)>,
+    /// 稳定代理的属性索引, 同样不需要保存
+    #[serde(skip)]
+    attrs: AttrIndex,
+}
+
+/// 属性索引的桶, 同一个桶中的代理协议, 匿名程度相同, 稳定率和延迟相近
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BucketKey {
+    protocol: Protocol,
+    anonymity: AnonymityLevel,
+    /// 稳定率 * 10 向下取整, 0 ~ 10
+    stability: u8,
+    /// 延迟 EWMA 所在的区间, 见 LATENCY_BOUNDS
+    latency: u8,
+}
+
+/// 桶中的代理与筛选条件的匹配程度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Match {
+    /// 全都不符合
+    None,
+    /// 需要逐个检查
+    Partial,
+    /// 全都符合
+    Full,
+}
+
+impl Match {
+    #[inline]
+    fn and(self, other: Match) -> Match {
+        match (self, other) {
+            (Match::None, _) | (_, Match::None) => Match::None,
+            (Match::Full, Match::Full) => Match::Full,
+            _ => Match::Partial,
+        }
+    }
+}
+
+impl BucketKey {
+    fn new(proxy: &Proxy, info: &_ProxyInfo) -> Self {
+        // 用整数计算, 保证桶的下界是准确的
+        let stability = if info.check_cnt() == 0 {
+            0
+        } else {
+            (info.success * 10 / info.check_cnt()) as u8
+        };
+        let latency = match info.latency_ewma {
+            Some(ewma) => LATENCY_BOUNDS
+                .iter()
+                .position(|&bound| ewma < f64::from(bound))
+                .unwrap_or(LATENCY_BOUNDS.len()) as u8,
+            None => UNKNOWN_LATENCY_BUCKET,
+        };
+        Self {
+            protocol: proxy.protocol(),
+            anonymity: proxy.anonymity(),
+            stability,
+            latency,
+        }
+    }
+
+    /// 判断桶中的代理是否符合筛选条件
+    fn matches(&self, query: &Query) -> Match {
+        if query
+            .protocol
+            .map_or(false, |protocol| protocol != self.protocol)
+            || query
+                .anonymity
+                .map_or(false, |anonymity| anonymity != self.anonymity)
+        {
+            return Match::None;
+        }
+        let stability = match query.stability {
+            None => Match::Full,
+            Some(stability) => {
+                let lower = f32::from(self.stability) / 10.0;
+                let upper = f32::from(self.stability + 1) / 10.0;
+                if lower >= stability {
+                    Match::Full
+                } else if upper <= stability {
+                    Match::None
+                } else {
+                    Match::Partial
+                }
+            }
+        };
+        let latency = match query.max_latency {
+            None => Match::Full,
+            Some(_) if self.latency == UNKNOWN_LATENCY_BUCKET => Match::None,
+            Some(max_latency) => {
+                let i = usize::from(self.latency);
+                let lower = if i == 0 { 0 } else { LATENCY_BOUNDS[i - 1] };
+                match LATENCY_BOUNDS.get(i) {
+                    _ if lower > max_latency => Match::None,
+                    // 区间是左闭右开的
+                    Some(&upper) if upper <= max_latency => Match::Full,
+                    _ => Match::Partial,
+                }
+            }
+        };
+        // 地理位置不在桶的属性中, 只能逐个检查
+        let geo = if query.country.is_some() || query.asn.is_some() {
+            Match::Partial
+        } else {
+            Match::Full
+        };
+        stability.and(latency).and(geo)
+    }
+}
+
+/// 支持 O(1) 插入, 删除和随机取的集合
+#[derive(Debug, Default)]
+struct Bucket {
+    keys: Vec<SocketAddr>,
+    pos: HashMap<SocketAddr, usize>,
+}
+
+impl Bucket {
+    fn insert(&mut self, key: SocketAddr) {
+        self.pos.insert(key, self.keys.len());
+        self.keys.push(key);
+    }
+
+    fn remove(&mut self, key: &SocketAddr) {
+        if let Some(i) = self.pos.remove(key) {
+            self.keys.swap_remove(i);
+            if let Some(moved) = self.keys.get(i) {
+                self.pos.insert(*moved, i);
+            }
+        }
+    }
+}
+
+/// 稳定代理按属性分桶的索引
+/// 桶的数量只和属性的取值数量有关, 所以筛选时遍历所有桶也是 O(1) 的
+#[derive(Debug, Default)]
+struct AttrIndex {
+    buckets: HashMap<BucketKey, Bucket>,
+    /// 代理当前所在的桶
+    location: HashMap<SocketAddr, BucketKey>,
+}
+
+impl AttrIndex {
+    /// 插入代理, 或者在属性变化时移动到新的桶
+    fn insert(&mut self, key: SocketAddr, bucket: BucketKey) {
+        if let Some(old) = self.location.insert(key, bucket) {
+            if old == bucket {
+                return;
+            }
+            self.remove_from(&key, &old);
+        }
+        self.buckets.entry(bucket).or_default().insert(key);
+    }
+
+    fn remove(&mut self, key: &SocketAddr) {
+        if let Some(old) = self.location.remove(key) {
+            self.remove_from(key, &old);
+        }
+    }
+
+    fn remove_from(&mut self, key: &SocketAddr, bucket: &BucketKey) {
+        if let Some(item) = self.buckets.get_mut(bucket) {
+            item.remove(key);
+            if item.keys.is_empty() {
+                self.buckets.remove(bucket);
+            }
+        }
+    }
+
+    /// 找出所有符合条件的代理, 需要多次随机取的话只用找一次
+    /// 只有落在条件边界上的桶需要用 check 逐个检查
+    fn candidates<F: Fn(&SocketAddr) -> bool>(&self, query: &Query, check: F) -> Candidates<'_> {
+        let mut candidates = Candidates::default();
+        for (bucket_key, bucket) in &self.buckets {
+            match bucket_key.matches(query) {
+                Match::None => (),
+                Match::Partial => candidates
+                    .partial
+                    .extend(bucket.keys.iter().filter(|key| check(key))),
+                Match::Full => {
+                    candidates.total += bucket.keys.len();
+                    candidates.full.push(bucket);
+                }
+            }
+        }
+        candidates
+    }
+
+    /// 随机取出一个符合条件的代理
+    fn choose<R: Rng, F: Fn(&SocketAddr) -> bool>(
+        &self,
+        rng: &mut R,
+        query: &Query,
+        check: F,
+    ) -> Option<SocketAddr> {
+        self.candidates(query, check).choose(rng)
+    }
+}
+
+/// 属性索引中符合条件的代理, 完全符合的桶不用展开
+#[derive(Debug, Default)]
+struct Candidates<'a> {
+    full: Vec<&'a Bucket>,
+    /// full 中代理的总数
+    total: usize,
+    /// 部分符合的桶中逐个检查过的代理
+    partial: Vec<SocketAddr>,
+}
+
+impl Candidates<'_> {
+    fn len(&self) -> usize {
+        self.total + self.partial.len()
+    }
+
+    /// 等概率随机取出一个
+    fn choose<R: Rng>(&self, rng: &mut R) -> Option<SocketAddr> {
+        if self.len() == 0 {
+            return None;
+        }
+        let mut n = rng.gen_range(0, self.len());
+        for bucket in &self.full {
+            if n < bucket.keys.len() {
+                return Some(bucket.keys[n]);
+            }
+            n -= bucket.keys.len();
+        }
+        self.partial.get(n).copied()
+    }
+}
+
+impl ProxyListInner {
+    /// 根据列表重建下标, 从磁盘读取后需要调用一次
+    fn rebuild_index(&mut self) {
+        self.index.clear();
+        for (i, proxy) in self.unstable.iter().enumerate() {
+            self.index.insert(proxy.get_key(), (false, i));
+        }
+        for (i, proxy) in self.stable.iter().enumerate() {
+            self.index.insert(proxy.get_key(), (true, i));
+        }
+    }
+
+    #[inline]
+    fn list_mut(&mut self, stable: bool) -> &mut Vec<Proxy> {
+        if stable {
+            &mut self.stable
+        } else {
+            &mut self.unstable
+        }
+    }
+
+    /// 添加代理到指定列表的末尾
+    fn push(&mut self, proxy: Proxy, stable: bool) {
+        let list = self.list_mut(stable);
+        let key = proxy.get_key();
+        list.push(proxy);
+        let i = list.len() - 1;
+        self.index.insert(key, (stable, i));
+    }
+
+    /// 更新代理的属性索引, 只有稳定代理需要索引
+    fn reindex(&mut self, key: &SocketAddr, info: &_ProxyInfo) {
+        let bucket = match self.get(key) {
+            Some((proxy, true)) => BucketKey::new(proxy, info),
+            _ => return self.attrs.remove(key),
+        };
+        self.attrs.insert(*key, bucket);
+    }
+
+    /// 从指定列表中取出代理, 代理不在这个列表中时返回 None
+    /// 用最后一个元素填补空位, 所以是 O(1) 的
+    fn take(&mut self, key: &SocketAddr, stable: bool) -> Option<Proxy> {
+        let (in_stable, i) = *self.index.get(key)?;
+        if in_stable != stable {
+            return None;
+        }
+        self.index.remove(key);
+        self.attrs.remove(key);
+        let list = self.list_mut(stable);
+        let proxy = list.swap_remove(i);
+        // 被移过来的代理需要更新下标
+        if let Some(moved) = list.get(i) {
+            let moved = moved.get_key();
+            self.index.insert(moved, (stable, i));
+        }
+        Some(proxy)
+    }
+
+    /// 从所在的列表中删除代理
+    fn remove(&mut self, key: &SocketAddr) -> Option<Proxy> {
+        let (stable, _) = *self.index.get(key)?;
+        self.take(key, stable)
+    }
+
+    /// 获取代理的可变引用
+    fn get_mut(&mut self, key: &SocketAddr) -> Option<&mut Proxy> {
+        let (stable, i) = *self.index.get(key)?;
+        self.list_mut(stable).get_mut(i)
+    }
+
+    #[inline]
+    fn get(&self, key: &SocketAddr) -> Option<(&Proxy, bool)> {
+        let (stable, i) = *self.index.get(key)?;
+        let list = if stable { &self.stable } else { &self.unstable };
+        list.get(i).map(|proxy| (proxy, stable))
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -26,6 +360,196 @@ pub struct _ProxyInfo {
     pub failed: u32,
     /// 连续失败次数, 这一项主要是防止一个高稳定性代理下线以后迟迟不能被剔除
     pub fail_times: u8,
+    /// 最近一次验证成功的延迟(ms)
+    #[serde(default)]
+    pub latency: Option<u32>,
+    /// 延迟的指数加权移动平均(ms)
+    #[serde(default)]
+    pub latency_ewma: Option<f64>,
+    /// 最近 LATENCY_WINDOW 次验证成功的延迟(ms)
+    #[serde(default)]
+    pub latency_history: VecDeque<u32>,
+    /// 对各个额外验证目标的验证结果
+    #[serde(default)]
+    pub targets: HashMap<String, TargetInfo>,
+    /// 下次验证的时间(unix 时间戳)
+    #[serde(default)]
+    pub next_check_at: u64,
+    /// 当前的验证间隔(secs)
+    #[serde(default)]
+    pub check_interval: u64,
+    /// 最近一次验证成功的时间(unix 时间戳)
+    #[serde(default)]
+    pub last_success_at: u64,
+    /// 代理的来源, 爬虫规则的名称或导入时指定的标记
+    #[serde(default)]
+    pub source: Option<String>,
+    /// 地理位置, 配置了 GeoIP 数据库时才有
+    #[serde(default)]
+    pub geo: Option<GeoInfo>,
+    /// 出口 IP, 即 judge 看到的来源 IP, 配置了 judge_url 时才有
+    /// 多个代理可能共用同一个出口, 比如同一台机器的多个端口
+    #[serde(default)]
+    pub exit_ip: Option<IpAddr>,
+}
+
+/// 代理对某个验证目标的验证结果
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TargetInfo {
+    /// 成功验证次数
+    pub success: u32,
+    /// 失败验证次数
+    pub failed: u32,
+    /// 连续失败次数
+    pub fail_times: u8,
+}
+
+impl TargetInfo {
+    /// 最近一次验证是否成功
+    #[inline]
+    pub fn available(&self) -> bool {
+        self.success > 0 && self.fail_times == 0
+    }
+}
+
+/// 筛选条件
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// 协议
+    pub protocol: Option<Protocol>,
+    /// 匿名程度
+    pub anonymity: Option<AnonymityLevel>,
+    /// 最低稳定率
+    pub stability: Option<f32>,
+    /// 最大延迟(ms)
+    pub max_latency: Option<u32>,
+    /// 能访问的验证目标
+    pub target: Option<String>,
+    /// 国家/地区
+    pub country: Option<CountryFilter>,
+    /// 自治系统号
+    pub asn: Option<u32>,
+}
+
+impl Filter {
+    /// 从用户输入构造筛选条件, 协议或匿名程度无法识别时返回错误
+    pub fn parse(
+        protocol: Option<String>,
+        anonymity: Option<String>,
+        stability: Option<f32>,
+        max_latency: Option<u32>,
+        target: Option<String>,
+        country: Option<String>,
+        asn: Option<String>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            protocol: protocol.map(|s| Protocol::parse_strict(&s)).transpose()?,
+            anonymity: anonymity
+                .map(|s| AnonymityLevel::parse_strict(&s))
+                .transpose()?,
+            stability,
+            max_latency,
+            target,
+            country: country.map(|s| s.parse()).transpose()?,
+            asn: asn.map(|s| parse_asn(&s)).transpose()?,
+        })
+    }
+
+    /// 是否没有任何筛选条件
+    pub fn is_empty(&self) -> bool {
+        self.protocol.is_none()
+            && self.anonymity.is_none()
+            && self.stability.is_none()
+            && self.max_latency.is_none()
+            && self.target.is_none()
+            && self.country.is_none()
+            && self.asn.is_none()
+    }
+
+    /// 单个代理是否符合筛选条件
+    fn accepts(&self, proxy: &Proxy, item: &_ProxyInfo) -> bool {
+        self.protocol
+            .map_or(true, |protocol| proxy.protocol() == protocol)
+            && self
+                .anonymity
+                .map_or(true, |anonymity| proxy.anonymity() == anonymity)
+            && Query::new(self).check(item)
+            && self.target.as_ref().map_or(true, |target| {
+                item.targets
+                    .get(target)
+                    .map_or(false, TargetInfo::available)
+            })
+    }
+}
+
+/// 取代理的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// 等概率随机
+    Uniform,
+    /// 按评分加权随机
+    Weighted,
+    /// 评分最高的代理
+    Best,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "uniform" => Ok(Strategy::Uniform),
+            "weighted" => Ok(Strategy::Weighted),
+            "best" => Ok(Strategy::Best),
+            _ => Err(format_err!("未知的策略: {}", s)),
+        }
+    }
+}
+
+/// 解析过的筛选条件, 用于查询属性索引
+#[derive(Debug)]
+struct Query {
+    protocol: Option<Protocol>,
+    anonymity: Option<AnonymityLevel>,
+    stability: Option<f32>,
+    max_latency: Option<u32>,
+    country: Option<CountryFilter>,
+    asn: Option<u32>,
+}
+
+impl Query {
+    fn new(filter: &Filter) -> Self {
+        Self {
+            protocol: filter.protocol,
+            anonymity: filter.anonymity,
+            stability: filter.stability,
+            max_latency: filter.max_latency,
+            country: filter.country.clone(),
+            asn: filter.asn,
+        }
+    }
+
+    /// 逐个检查代理的稳定率, 延迟和地理位置
+    fn check(&self, item: &_ProxyInfo) -> bool {
+        let stability = self.stability.map_or(true, |stability| {
+            let failed = item.failed as f32;
+            let success = item.success as f32;
+            success / (success + failed) >= stability
+        });
+        let latency = self.max_latency.map_or(true, |max_latency| {
+            item.latency_ewma
+                .map_or(false, |latency| latency <= f64::from(max_latency))
+        });
+        let geo = item.geo.as_ref();
+        let country = self.country.as_ref().map_or(true, |country| {
+            country.matches(geo.and_then(|geo| geo.country.as_deref()))
+        });
+        let asn = self
+            .asn
+            .map_or(true, |asn| geo.and_then(|geo| geo.asn) == Some(asn));
+        stability && latency && country && asn
+    }
 }
 
 impl _ProxyInfo {
@@ -38,15 +562,176 @@ impl _ProxyInfo {
     pub fn check_cnt(&self) -> u32 {
         self.success + self.failed
     }
+
+    /// 记录一次延迟
+    pub fn update_latency(&mut self, latency: u32) {
+        self.latency = Some(latency);
+        self.latency_ewma = Some(match self.latency_ewma {
+            Some(ewma) => LATENCY_ALPHA * f64::from(latency) + (1.0 - LATENCY_ALPHA) * ewma,
+            None => f64::from(latency),
+        });
+        if self.latency_history.len() >= LATENCY_WINDOW {
+            self.latency_history.pop_front();
+        }
+        self.latency_history.push_back(latency);
+    }
+
+    /// 根据配置计算代理的评分, 0 ~ 1, 越高越好
+    pub fn score(&self, config: &ScoreConfig, now: u64) -> f64 {
+        let stability = if self.check_cnt() == 0 {
+            0.0
+        } else {
+            self.stability()
+        };
+        let latency = self.latency_ewma.unwrap_or(UNKNOWN_LATENCY);
+        let latency = config.latency_ref / (config.latency_ref + latency);
+        // 没有成功记录的话(包括旧版本的数据), 时效得分为 0
+        let recency = if self.last_success_at == 0 {
+            0.0
+        } else {
+            let age = now.saturating_sub(self.last_success_at) as f64;
+            0.5f64.powf(age / config.recency_half_life)
+        };
+        let fail_times = 0.5f64.powi(i32::from(self.fail_times));
+        stability.powf(config.stability)
+            * latency.powf(config.latency)
+            * recency.powf(config.recency)
+            * fail_times.powf(config.fail_times)
+    }
+
+    /// 最近几次延迟的百分位数, p 取 0.0 ~ 1.0
+    pub fn latency_percentile(&self, p: f64) -> Option<u32> {
+        if self.latency_history.is_empty() {
+            return None;
+        }
+        let mut history = self.latency_history.iter().copied().collect::<Vec<_>>();
+        history.sort_unstable();
+        let index = ((history.len() - 1) as f64 * p).round() as usize;
+        Some(history[index])
+    }
+}
+
+/// 代理的延迟统计(ms)
+#[derive(Debug, Serialize)]
+pub struct LatencyStats {
+    /// 最近一次
+    pub last: Option<u32>,
+    /// 指数加权移动平均
+    pub ewma: Option<f64>,
+    /// 中位数
+    pub p50: Option<u32>,
+    /// 90% 分位数
+    pub p90: Option<u32>,
+}
+
+/// 一次借出代理的租约
+#[derive(Debug, Clone, Serialize)]
+pub struct Lease {
+    /// 租约 ID, 归还时使用
+    pub id: String,
+    /// 借出的代理
+    pub proxy: Proxy,
+    /// 到期时间(unix 时间戳), 到期后自动归还
+    pub expires_at: u64,
+}
+
+/// 所有未归还的租约
+#[derive(Debug, Default)]
+pub struct LeaseTable {
+    /// 租约 ID -> 租约
+    leases: HashMap<String, Lease>,
+    /// 代理当前被借出的次数
+    count: HashMap<SocketAddr, usize>,
+    /// 按到期时间排序的小根堆, 已归还的租约在弹出时跳过
+    expiry: BinaryHeap<Reverse<(u64, String)>>,
+}
+
+impl LeaseTable {
+    /// 归还所有到期的租约
+    fn expire(&mut self, now: u64) {
+        while let Some(Reverse((time, _))) = self.expiry.peek() {
+            if *time > now {
+                break;
+            }
+            let Reverse((_, id)) = self.expiry.pop().unwrap();
+            self.release(&id);
+        }
+    }
+
+    fn release(&mut self, id: &str) -> bool {
+        let lease = match self.leases.remove(id) {
+            Some(lease) => lease,
+            None => return false,
+        };
+        let key = lease.proxy.get_key();
+        if let Some(count) = self.count.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                self.count.remove(&key);
+            }
+        }
+        true
+    }
+
+    /// 代理被借出的次数
+    #[inline]
+    fn count(&self, key: &SocketAddr) -> usize {
+        self.count.get(key).copied().unwrap_or(0)
+    }
 }
 
 /// 代理池
 /// O(1) 的插入时间复杂度
 /// O(1) 的随机取时间复杂度
+/// O(1) 的删除及稳定/不稳定之间移动的时间复杂度
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ProxyPool {
     list: ProxyList,
     info: ProxyInfo,
+    /// 验证计划, 可以从 info 中恢复, 不需要保存
+    #[serde(skip)]
+    schedule: Schedule,
+    /// 借出的代理, 租约都很短, 不需要保存
+    #[serde(skip)]
+    leases: Leases,
+    /// 黑名单, 单独保存
+    #[serde(skip)]
+    blacklist: RwLock<Blacklist>,
+    /// 会话绑定的代理, 重启后重新分配即可, 不需要保存
+    #[serde(skip)]
+    sessions: Sessions,
+    /// GeoIP 数据库, 用于给新加入的代理查询地理位置
+    #[serde(skip)]
+    geoip: RwLock<Option<Arc<GeoIp>>>,
+}
+
+/// 记录会话绑定的代理, 会话数量达到上限时先挤掉最早过期的会话
+/// 免得客户端不停地创建新会话, 让会话表无限增长
+fn insert_session(
+    sessions: &mut HashMap<String, (SocketAddr, u64)>,
+    session: &str,
+    key: SocketAddr,
+    expires_at: u64,
+    max_sessions: usize,
+) {
+    if !sessions.contains_key(session) && sessions.len() >= max_sessions {
+        let oldest = sessions
+            .iter()
+            .min_by_key(|(_, (_, expires_at))| *expires_at)
+            .map(|(id, _)| id.clone());
+        if let Some(oldest) = oldest {
+            sessions.remove(&oldest);
+        }
+    }
+    sessions.insert(session.to_owned(), (key, expires_at));
+}
+
+/// 当前的 unix 时间戳
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("系统时间早于 1970 年")
+        .as_secs()
 }
 
 // TODO: 一堆 unwrap() ?
@@ -57,33 +742,215 @@ impl ProxyPool {
         Default::default()
     }
 
+    /// 重建代理在列表中的下标及属性索引, 从磁盘读取后需要调用一次
+    pub fn init_index(&self) {
+        let mut proxy_list = self.list.write().unwrap();
+        let proxy_info = self.info.read().unwrap();
+        proxy_list.rebuild_index();
+        for (key, item) in proxy_info.iter() {
+            proxy_list.reindex(key, item);
+        }
+    }
+
+    /// 根据 info 重建验证计划, 从磁盘读取后需要调用一次
+    pub fn init_schedule(&self) {
+        let info = self.info.read().unwrap();
+        let mut schedule = self.schedule.lock().unwrap();
+        schedule.clear();
+        for (key, item) in info.iter() {
+            schedule.push(Reverse((item.next_check_at, *key)));
+        }
+    }
+
+    /// 取出所有到期需要验证的代理, 返回 (稳定代理, 不稳定代理)
+    /// 取出的代理验证完后需要调用 reschedule 重新安排, 否则不会再被验证
+    pub fn take_due(self: Arc<Self>, now: u64) -> (Vec<Proxy>, Vec<Proxy>) {
+        let mut due = HashSet::new();
+        {
+            let info = self.info.read().unwrap();
+            let mut schedule = self.schedule.lock().unwrap();
+            while let Some(&Reverse((time, key))) = schedule.peek() {
+                if time > now {
+                    break;
+                }
+                schedule.pop();
+                // 代理已被删除或者已被重新安排的话, 这一项就过期了
+                if info
+                    .get(&key)
+                    .map_or(false, |item| item.next_check_at == time)
+                {
+                    due.insert(key);
+                }
+            }
+        }
+        let proxy_list = self.list.read().unwrap();
+        let (mut stable, mut unstable) = (vec![], vec![]);
+        for key in due {
+            match proxy_list.get(&key) {
+                Some((proxy, true)) => stable.push(proxy.clone()),
+                Some((proxy, false)) => unstable.push(proxy.clone()),
+                None => (),
+            }
+        }
+        (stable, unstable)
+    }
+
+    /// 安排代理的下次验证
+    /// changed 为 true 表示代理状态发生了变化, 验证间隔重置为 min_interval, 否则翻倍
+    pub fn reschedule(
+        self: Arc<Self>,
+        proxy: &Proxy,
+        changed: bool,
+        min_interval: u64,
+        max_interval: u64,
+    ) {
+        let mut info = self.info.write().unwrap();
+        let item = match info.get_mut(&proxy.get_key()) {
+            Some(item) => item,
+            // 已经被删除了
+            None => return,
+        };
+        item.check_interval = if changed || item.check_interval == 0 {
+            min_interval
+        } else {
+            (item.check_interval * 2)
+                .min(max_interval)
+                .max(min_interval)
+        };
+        item.next_check_at = unix_now() + item.check_interval;
+        self.schedule
+            .lock()
+            .unwrap()
+            .push(Reverse((item.next_check_at, proxy.get_key())));
+    }
+
+    /// 让代理在下一轮验证时就被验证
+    pub fn check_soon(self: Arc<Self>, proxy: &Proxy) {
+        let mut info = self.info.write().unwrap();
+        if let Some(item) = info.get_mut(&proxy.get_key()) {
+            item.next_check_at = unix_now();
+            self.schedule
+                .lock()
+                .unwrap()
+                .push(Reverse((item.next_check_at, proxy.get_key())));
+        }
+    }
+
     /// 移动代理到稳定列表中
     pub fn move_to_stable(self: Arc<Self>, proxy: &Proxy) {
         let mut proxy_list = self.list.write().unwrap();
-        let proxy = proxy_list.unstable.remove_item(&proxy).unwrap();
-        proxy_list.stable.push(proxy);
+        if let Some(proxy) = proxy_list.take(&proxy.get_key(), false) {
+            let key = proxy.get_key();
+            proxy_list.push(proxy, true);
+            if let Some(item) = self.info.read().unwrap().get(&key) {
+                proxy_list.reindex(&key, item);
+            }
+        }
     }
 
     /// 移动代理到不稳定列表中
     pub fn move_to_unstable(self: Arc<Self>, proxy: &Proxy) {
         let mut proxy_list = self.list.write().unwrap();
-        let proxy = proxy_list.stable.remove_item(&proxy).unwrap();
-        proxy_list.unstable.push(proxy);
+        if let Some(proxy) = proxy_list.take(&proxy.get_key(), true) {
+            proxy_list.push(proxy, false);
+        }
     }
 
     /// 从不稳定列表中删除一个代理
     pub fn remove_unstable(self: Arc<Self>, proxy: &Proxy) {
-        // 反正都用 rocket 了, unstable feature 用起来!
         let mut proxy_list = self.list.write().unwrap();
-        proxy_list.unstable.remove_item(proxy).unwrap();
-        self.info.write().unwrap().remove(&proxy.get_key()).unwrap();
+        if proxy_list.take(&proxy.get_key(), false).is_some() {
+            self.info.write().unwrap().remove(&proxy.get_key());
+        }
+    }
+
+    /// 删除一个代理, 不管它在哪个列表中, 返回代理是否存在
+    pub fn remove(self: Arc<Self>, key: &SocketAddr) -> bool {
+        let mut proxy_list = self.list.write().unwrap();
+        if proxy_list.remove(key).is_some() {
+            self.info.write().unwrap().remove(key);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 设置黑名单, 从磁盘读取后需要调用一次
+    pub fn set_blacklist(&self, blacklist: Blacklist) {
+        *self.blacklist.write().unwrap() = blacklist;
+    }
+
+    /// 设置 GeoIP 数据库, 并给还没有地理位置信息的代理补上
+    pub fn set_geoip(&self, geoip: Option<GeoIp>) {
+        let geoip = geoip.map(Arc::new);
+        // 先放开 geoip 的锁再锁 info, 和 extend_unstable 的加锁顺序保持一致
+        *self.geoip.write().unwrap() = geoip.clone();
+        if let Some(geoip) = geoip {
+            let mut proxy_info = self.info.write().unwrap();
+            for (key, item) in proxy_info.iter_mut() {
+                if item.geo.is_none() {
+                    item.geo = geoip.lookup(key.ip());
+                }
+            }
+        }
+    }
+
+    /// 获取黑名单的引用
+    pub fn get_blacklist(&self) -> RwLockReadGuard<Blacklist> {
+        self.blacklist.read().unwrap()
+    }
+
+    /// 封禁并删除符合规则的代理, 返回删除的代理数量
+    pub fn ban(self: Arc<Self>, rule: BanRule) -> Result<usize, Error> {
+        self.blacklist.write().unwrap().ban(rule)?;
+
+        let mut proxy_list = self.list.write().unwrap();
+        let mut proxy_info = self.info.write().unwrap();
+        let banned = proxy_list
+            .stable
+            .iter()
+            .chain(proxy_list.unstable.iter())
+            .filter(|proxy| rule.matches(proxy))
+            .map(Proxy::get_key)
+            .collect::<Vec<_>>();
+        for key in &banned {
+            proxy_list.remove(key);
+            proxy_info.remove(key);
+        }
+        Ok(banned.len())
+    }
+
+    /// 解除封禁, 返回规则之前是否存在
+    pub fn unban(self: Arc<Self>, rule: BanRule) -> Result<bool, Error> {
+        self.blacklist.write().unwrap().unban(rule)
     }
 
-    /// 从稳定列表中删除一个代理
-    pub fn _remove_stable(self: Arc<Self>, proxy: &Proxy) {
+    /// 更新代理的匿名程度
+    pub fn set_anonymity(self: Arc<Self>, proxy: &Proxy, anonymity: AnonymityLevel) {
         let mut proxy_list = self.list.write().unwrap();
-        proxy_list.stable.remove_item(proxy).unwrap();
-        self.info.write().unwrap().remove(&proxy.get_key()).unwrap();
+        let key = proxy.get_key();
+        if let Some(proxy) = proxy_list.get_mut(&key) {
+            proxy.set_anonymity(anonymity);
+            if let Some(item) = self.info.read().unwrap().get(&key) {
+                proxy_list.reindex(&key, item);
+            }
+        }
+    }
+
+    /// 根据地址查找代理
+    pub fn get_proxy(self: Arc<Self>, key: &SocketAddr) -> Option<Proxy> {
+        let proxy_list = self.list.read().unwrap();
+        proxy_list.get(key).map(|(proxy, _)| proxy.clone())
+    }
+
+    /// 根据地址查找稳定列表中符合条件的代理
+    pub fn get_matching(self: Arc<Self>, key: &SocketAddr, filter: &Filter) -> Option<Proxy> {
+        let proxy_list = self.list.read().unwrap();
+        let proxy_info = self.info.read().unwrap();
+        match (proxy_list.get(key), proxy_info.get(key)) {
+            (Some((proxy, true)), Some(item)) if filter.accepts(proxy, item) => Some(proxy.clone()),
+            _ => None,
+        }
     }
 
     /// 从稳定列表中随机取出一个代理
@@ -94,24 +961,29 @@ impl ProxyPool {
     }
 
     /// 根据条件筛选代理
-    pub fn select(
-        self: Arc<Self>,
-        ssl_type: Option<String>,
-        anonymity: Option<String>,
-        stability: Option<f32>,
-    ) -> Vec<Proxy> {
+    pub fn select(self: Arc<Self>, filter: Filter) -> Vec<Proxy> {
+        // 地理位置的检查直接交给 Query
+        let query = Query::new(&filter);
+        let Filter {
+            protocol,
+            anonymity,
+            stability,
+            max_latency,
+            target,
+            ..
+        } = filter;
         let proxy_list = self.list.read().unwrap();
         let proxy_info = self.info.read().unwrap();
+        // 多个闭包都需要用到 proxy_info, 所以只 move 引用进去
+        let proxy_info = &*proxy_info;
         // 此处将所有 Iterator 泛化为 Iterator<Item = &Proxy>, 以便使用同一个变量存储中间结果
         // 省去 collect 开销
         let mut iter = Box::new(proxy_list.stable.iter()) as Box<dyn Iterator<Item = &Proxy>>;
-        if let Some(ssl_type) = ssl_type {
-            let ssl_type = ssl_type.parse().unwrap();
-            iter = Box::new(iter.filter(move |proxy| proxy.ssl_type() == ssl_type))
+        if let Some(protocol) = protocol {
+            iter = Box::new(iter.filter(move |proxy| proxy.protocol() == protocol))
                 as Box<dyn Iterator<Item = &Proxy>>;
         }
         if let Some(anonymity) = anonymity {
-            let anonymity = anonymity.parse().unwrap();
             iter = Box::new(iter.filter(move |proxy| proxy.anonymity() == anonymity))
                 as Box<dyn Iterator<Item = &Proxy>>;
         }
@@ -123,20 +995,321 @@ impl ProxyPool {
                 success / (success + failed) >= stability
             })) as Box<dyn Iterator<Item = &Proxy>>;
         }
+        if let Some(max_latency) = max_latency {
+            iter = Box::new(iter.filter(move |proxy| {
+                let item = &proxy_info[&proxy.get_key()];
+                item.latency_ewma
+                    .map_or(false, |latency| latency <= f64::from(max_latency))
+            })) as Box<dyn Iterator<Item = &Proxy>>;
+        }
+        if let Some(target) = target {
+            iter = Box::new(iter.filter(move |proxy| {
+                let item = &proxy_info[&proxy.get_key()];
+                item.targets
+                    .get(&target)
+                    .map_or(false, TargetInfo::available)
+            })) as Box<dyn Iterator<Item = &Proxy>>;
+        }
+        if query.country.is_some() || query.asn.is_some() {
+            iter = Box::new(iter.filter(move |proxy| query.check(&proxy_info[&proxy.get_key()])))
+                as Box<dyn Iterator<Item = &Proxy>>;
+        }
         iter.cloned().collect()
         // FIXME: 究极 clone
     }
 
-    pub fn select_random(
+    /// 根据条件随机取出一个代理
+    /// prefer_fast 为 true 时按延迟的倒数加权, 延迟越低越容易被选中
+    pub fn select_random(self: Arc<Self>, filter: Filter, prefer_fast: bool) -> Option<Proxy> {
+        let mut rng = thread_rng();
+        // 其他条件都可以走属性索引, 不用遍历整个列表
+        if !prefer_fast && filter.target.is_none() {
+            let query = Query::new(&filter);
+            let proxy_list = self.list.read().unwrap();
+            let proxy_info = self.info.read().unwrap();
+            let key = proxy_list.attrs.choose(&mut rng, &query, |key| {
+                proxy_info.get(key).map_or(false, |item| query.check(item))
+            })?;
+            return proxy_list.get(&key).map(|(proxy, _)| proxy.clone());
+        }
+        let proxies = self.clone().select(filter);
+        if prefer_fast {
+            let proxy_info = self.info.read().unwrap();
+            proxies
+                .choose_weighted(&mut rng, |proxy| {
+                    let latency = proxy_info
+                        .get(&proxy.get_key())
+                        .and_then(|item| item.latency_ewma)
+                        .unwrap_or(UNKNOWN_LATENCY);
+                    // 防止延迟为 0 时除零
+                    1.0 / latency.max(1.0)
+                })
+                .ok()
+                .cloned()
+        } else {
+            proxies.choose(&mut rng).cloned()
+        }
+    }
+
+    /// 根据条件和评分取出一个代理
+    pub fn select_by_score(
         self: Arc<Self>,
-        ssl_type: Option<String>,
-        anonymity: Option<String>,
-        stability: Option<f32>,
+        filter: Filter,
+        strategy: Strategy,
+        config: &ScoreConfig,
     ) -> Option<Proxy> {
+        if strategy == Strategy::Uniform {
+            return self.select_random(filter, false);
+        }
         let mut rng = thread_rng();
-        self.select(ssl_type, anonymity, stability)
+        // weighted 先在属性索引上拒绝采样: 等概率取一个代理, 以评分(0 ~ 1)为概率接受
+        // 选中的概率和按评分加权相同, 但不用遍历和 clone 整个列表
+        // 评分普遍很低时可能一直被拒绝, 这时再退回遍历
+        if strategy == Strategy::Weighted && filter.target.is_none() {
+            let query = Query::new(&filter);
+            let proxy_list = self.list.read().unwrap();
+            let proxy_info = self.info.read().unwrap();
+            let now = unix_now();
+            let candidates = proxy_list.attrs.candidates(&query, |key| {
+                proxy_info.get(key).map_or(false, |item| query.check(item))
+            });
+            for _ in 0..MAX_REJECTIONS {
+                // 没有符合条件的代理
+                let key = candidates.choose(&mut rng)?;
+                let score = proxy_info
+                    .get(&key)
+                    .map_or(0.0, |item| item.score(config, now));
+                if rng.gen::<f64>() < score {
+                    return proxy_list.get(&key).map(|(proxy, _)| proxy.clone());
+                }
+            }
+        }
+        let proxies = self.clone().select(filter);
+        let proxy_info = self.info.read().unwrap();
+        let now = unix_now();
+        let score = |proxy: &Proxy| {
+            proxy_info
+                .get(&proxy.get_key())
+                .map_or(0.0, |item| item.score(config, now))
+        };
+        match strategy {
+            Strategy::Best => proxies
+                .iter()
+                .max_by(|a, b| score(a).partial_cmp(&score(b)).unwrap_or(Ordering::Equal))
+                .cloned(),
+            // 所有代理评分都为 0 时退化为等概率随机
+            _ => proxies
+                .choose_weighted(&mut rng, score)
+                .ok()
+                .or_else(|| proxies.choose(&mut rng))
+                .cloned(),
+        }
+    }
+
+    /// 根据条件随机取出至多 count 个不重复的代理(无放回抽样)
+    /// 权重和 select_random / select_by_score 相同, prefer_fast 优先于 strategy
+    /// distinct_exit 为 true 时取出的代理出口 IP 各不相同, 出口未知的代理以自身的 IP 作为出口
+    pub fn sample(
+        self: Arc<Self>,
+        filter: Filter,
+        count: usize,
+        prefer_fast: bool,
+        strategy: Strategy,
+        config: &ScoreConfig,
+        distinct_exit: bool,
+    ) -> Vec<Proxy> {
+        let mut rng = thread_rng();
+        let mut proxies = self.clone().select(filter);
+        if !prefer_fast && strategy == Strategy::Uniform {
+            if !distinct_exit {
+                return proxies.choose_multiple(&mut rng, count).cloned().collect();
+            }
+            proxies.shuffle(&mut rng);
+            return self.distinct_exits(proxies, count);
+        }
+
+        let proxy_info = self.info.read().unwrap();
+        let now = unix_now();
+        let weight = |proxy: &Proxy| {
+            let item = proxy_info.get(&proxy.get_key());
+            if prefer_fast {
+                let latency = item
+                    .and_then(|item| item.latency_ewma)
+                    .unwrap_or(UNKNOWN_LATENCY);
+                1.0 / latency.max(1.0)
+            } else {
+                item.map_or(0.0, |item| item.score(config, now))
+            }
+        };
+        // best 直接取评分最高的 count 个
+        // 其他策略使用 Efraimidis-Spirakis 算法加权抽样: 每个代理取 u^(1/w) 作为键, 保留最大的 count 个
+        // 权重很小时 u^(1/w) 会下溢成 0, 所以改为比较它的对数 ln(u) / w
+        // 权重为 0 的代理排在后面, 只有在其他代理都被选中后才会被随机选中
+        let mut keyed = proxies
+            .into_iter()
+            .map(|proxy| {
+                let w = weight(&proxy);
+                let key = if !prefer_fast && strategy == Strategy::Best {
+                    (true, w)
+                } else if w > 0.0 {
+                    // 1 - u 的取值范围是 (0, 1], 避免 ln(0)
+                    (true, (1.0 - rng.gen::<f64>()).ln() / w)
+                } else {
+                    (false, rng.gen::<f64>())
+                };
+                (key, proxy)
+            })
+            .collect::<Vec<_>>();
+        keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        let proxies = keyed.into_iter().map(|(_, proxy)| proxy);
+        if distinct_exit {
+            drop(proxy_info);
+            self.distinct_exits(proxies, count)
+        } else {
+            proxies.take(count).collect()
+        }
+    }
+
+    /// 按顺序取出至多 count 个出口 IP 不同的代理
+    fn distinct_exits<T: IntoIterator<Item = Proxy>>(
+        &self,
+        proxies: T,
+        count: usize,
+    ) -> Vec<Proxy> {
+        let proxy_info = self.info.read().unwrap();
+        let mut exits = HashSet::new();
+        proxies
+            .into_iter()
+            .filter(|proxy| {
+                let key = proxy.get_key();
+                let exit = proxy_info
+                    .get(&key)
+                    .and_then(|item| item.exit_ip)
+                    .unwrap_or_else(|| key.ip());
+                exits.insert(exit)
+            })
+            .take(count)
+            .collect()
+    }
+
+    /// 按出口 IP 分组列出稳定列表中的代理, 共用出口的代理多的组排在前面
+    /// 还没有验证出出口 IP 的代理不在结果中
+    pub fn exit_groups(self: Arc<Self>) -> Vec<(IpAddr, Vec<Proxy>)> {
+        let proxy_list = self.list.read().unwrap();
+        let proxy_info = self.info.read().unwrap();
+        let mut groups: HashMap<IpAddr, Vec<Proxy>> = HashMap::new();
+        for proxy in &proxy_list.stable {
+            if let Some(exit_ip) = proxy_info
+                .get(&proxy.get_key())
+                .and_then(|item| item.exit_ip)
+            {
+                groups.entry(exit_ip).or_default().push(proxy.clone());
+            }
+        }
+        let mut groups = groups.into_iter().collect::<Vec<_>>();
+        groups.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(&b.0)));
+        groups
+    }
+
+    /// 借出一个符合条件的代理, ttl 秒后自动归还
+    /// 已被借出 max_per_proxy 次的代理不会再被借出
+    pub fn checkout(
+        self: Arc<Self>,
+        filter: Filter,
+        ttl: u64,
+        max_per_proxy: usize,
+    ) -> Option<Lease> {
+        let mut rng = thread_rng();
+        let now = unix_now();
+        let proxies = self.clone().select(filter);
+        let mut leases = self.leases.lock().unwrap();
+        leases.expire(now);
+        let proxy = proxies
+            .into_iter()
+            .filter(|proxy| leases.count(&proxy.get_key()) < max_per_proxy)
+            .collect::<Vec<_>>()
             .choose(&mut rng)
-            .cloned()
+            .cloned()?;
+
+        let id = loop {
+            let id = format!("{:016x}", rng.gen::<u64>());
+            if !leases.leases.contains_key(&id) {
+                break id;
+            }
+        };
+        let lease = Lease {
+            id: id.clone(),
+            proxy,
+            expires_at: now + ttl,
+        };
+        *leases.count.entry(lease.proxy.get_key()).or_default() += 1;
+        leases.expiry.push(Reverse((lease.expires_at, id.clone())));
+        leases.leases.insert(id, lease.clone());
+        Some(lease)
+    }
+
+    /// 提前归还代理, 租约不存在(或已到期)时返回 false
+    pub fn release(self: Arc<Self>, id: &str) -> bool {
+        let mut leases = self.leases.lock().unwrap();
+        leases.expire(unix_now());
+        leases.release(id)
+    }
+
+    /// 会话当前绑定的代理, 顺便清理过期的会话
+    pub fn session_proxy(&self, session: &str) -> Option<SocketAddr> {
+        let now = unix_now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        sessions.get(session).map(|(key, _)| *key)
+    }
+
+    /// 把会话绑定到代理上, ttl 秒后过期
+    pub fn bind_session(&self, session: &str, proxy: &Proxy, ttl: u64, max_sessions: usize) {
+        let now = unix_now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        insert_session(
+            &mut sessions,
+            session,
+            proxy.get_key(),
+            now + ttl,
+            max_sessions,
+        );
+    }
+
+    /// 取出会话绑定的代理, 并把会话的过期时间延长到 ttl 秒后
+    /// 会话不存在或已过期时用 pick 选一个新代理
+    /// 绑定的代理已被移出稳定列表(被验证线程降级或删除), 或不再符合筛选条件时, 也用 pick 换一个代理
+    /// 返回 (代理, 是否换过代理)
+    pub fn sticky<F>(
+        self: Arc<Self>,
+        session: &str,
+        ttl: u64,
+        max_sessions: usize,
+        filter: Filter,
+        pick: F,
+    ) -> Option<(Proxy, bool)>
+    where
+        F: FnOnce(Filter) -> Option<Proxy>,
+    {
+        // 整个过程都持有会话锁, 免得同一个会话的并发请求拿到不同的代理
+        let now = unix_now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        let bound = sessions.get(session).map(|(key, _)| *key);
+        let current = bound.and_then(|key| self.clone().get_matching(&key, &filter));
+        let (proxy, rotated) = match current {
+            Some(proxy) => (proxy, false),
+            None => (pick(filter)?, bound.is_some()),
+        };
+        insert_session(
+            &mut sessions,
+            session,
+            proxy.get_key(),
+            now + ttl,
+            max_sessions,
+        );
+        Some((proxy, rotated))
     }
 
     /// 获取未验证代理的引用
@@ -151,39 +1324,226 @@ impl ProxyPool {
 
     /// 代理验证失败计数 +1
     pub fn inc_failed_cnt(self: Arc<Self>, proxy: &Proxy) {
+        // 稳定率变化后需要更新属性索引
+        let mut proxy_list = self.list.write().unwrap();
         let mut info = self.info.write().unwrap();
-        let mut info = info.get_mut(&proxy.get_key()).unwrap();
-        info.failed += 1;
-        info.fail_times += 1;
+        // 客户端反馈时代理可能已经被删除了
+        if let Some(info) = info.get_mut(&proxy.get_key()) {
+            info.failed += 1;
+            info.fail_times = info.fail_times.saturating_add(1);
+            proxy_list.reindex(&proxy.get_key(), info);
+        }
     }
 
     /// 代理验证成功计数 +1
     pub fn inc_success_cnt(self: Arc<Self>, proxy: &Proxy) {
+        let mut proxy_list = self.list.write().unwrap();
         let mut info = self.info.write().unwrap();
-        let mut info = info.get_mut(&proxy.get_key()).unwrap();
-        info.success += 1;
-        info.fail_times = 0;
+        if let Some(info) = info.get_mut(&proxy.get_key()) {
+            info.success += 1;
+            info.fail_times = 0;
+            info.last_success_at = unix_now();
+            proxy_list.reindex(&proxy.get_key(), info);
+        }
     }
 
-    pub fn get_info(self: Arc<Self>, proxy: &Proxy) -> (f64, u32, u8) {
+    /// 记录一次验证的延迟
+    pub fn record_latency(self: Arc<Self>, proxy: &Proxy, latency: u32) {
+        let mut proxy_list = self.list.write().unwrap();
+        let mut info = self.info.write().unwrap();
+        if let Some(info) = info.get_mut(&proxy.get_key()) {
+            info.update_latency(latency);
+            proxy_list.reindex(&proxy.get_key(), info);
+        }
+    }
+
+    /// 记录 judge 看到的出口 IP, 返回出口是否发生了变化
+    pub fn set_exit_ip(self: Arc<Self>, proxy: &Proxy, exit_ip: IpAddr) -> bool {
+        let mut info = self.info.write().unwrap();
+        match info.get_mut(&proxy.get_key()) {
+            Some(info) if info.exit_ip != Some(exit_ip) => {
+                info.exit_ip = Some(exit_ip);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 记录代理对某个验证目标的验证结果
+    pub fn record_target(self: Arc<Self>, proxy: &Proxy, target: &str, success: bool) {
+        let mut info = self.info.write().unwrap();
+        if let Some(info) = info.get_mut(&proxy.get_key()) {
+            let target = info.targets.entry(target.to_owned()).or_default();
+            if success {
+                target.success += 1;
+                target.fail_times = 0;
+            } else {
+                target.failed += 1;
+                target.fail_times = target.fail_times.saturating_add(1);
+            }
+        }
+    }
+
+    /// 获取代理的延迟统计
+    pub fn get_latency_stats(self: Arc<Self>, key: &SocketAddr) -> Option<LatencyStats> {
         let info = self.info.read().unwrap();
-        let proxy_info = info.get(&proxy.get_key()).unwrap();
-        (
-            proxy_info.stability(),
-            proxy_info.check_cnt(),
-            proxy_info.fail_times,
-        )
+        info.get(key).map(|item| LatencyStats {
+            last: item.latency,
+            ewma: item.latency_ewma,
+            p50: item.latency_percentile(0.5),
+            p90: item.latency_percentile(0.9),
+        })
     }
 
-    pub fn extend_unstable<T: IntoIterator<Item = Proxy>>(self: Arc<Self>, iter: T) {
-        let mut proxy_info = self.info.write().unwrap();
+    /// 获取代理的稳定率, 验证次数和连续失败次数
+    /// 验证期间代理可能已经通过接口被删除或封禁了, 这时返回 None
+    pub fn get_info(self: Arc<Self>, proxy: &Proxy) -> Option<(f64, u32, u8)> {
+        let info = self.info.read().unwrap();
+        info.get(&proxy.get_key()).map(|proxy_info| {
+            (
+                proxy_info.stability(),
+                proxy_info.check_cnt(),
+                proxy_info.fail_times,
+            )
+        })
+    }
+
+    /// 添加新代理到不稳定列表中, 已存在或被封禁的代理会被跳过
+    /// source 为代理的来源, 返回实际添加的数量
+    pub fn extend_unstable<T: IntoIterator<Item = Proxy>>(
+        self: Arc<Self>,
+        iter: T,
+        source: &str,
+    ) -> usize {
+        // 和其他方法保持一致, 先锁 list 再锁 info, 避免死锁
         let mut proxy_list = self.list.write().unwrap();
+        let mut proxy_info = self.info.write().unwrap();
+        let blacklist = self.blacklist.read().unwrap();
+        let geoip = self.geoip.read().unwrap();
+        let mut added = 0;
         for proxy in iter {
             let exist = proxy_info.get(&proxy.get_key()).is_some();
-            if !exist {
-                proxy_info.insert(proxy.get_key(), Default::default());
-                proxy_list.unstable.push(proxy);
+            if !exist && !blacklist.contains(&proxy) {
+                proxy_info.insert(
+                    proxy.get_key(),
+                    _ProxyInfo {
+                        source: Some(source.to_owned()),
+                        geo: geoip.as_ref().and_then(|geoip| geoip.lookup(proxy.ip())),
+                        ..Default::default()
+                    },
+                );
+                // next_check_at 为 0, 下一轮就会被验证
+                self.schedule
+                    .lock()
+                    .unwrap()
+                    .push(Reverse((0, proxy.get_key())));
+                proxy_list.push(proxy, false);
+                added += 1;
             }
         }
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(stability: u8, latency: u8) -> BucketKey {
+        BucketKey {
+            protocol: Protocol::HTTPS,
+            anonymity: AnonymityLevel::Elite,
+            stability,
+            latency,
+        }
+    }
+
+    fn query(stability: Option<f32>, max_latency: Option<u32>) -> Query {
+        Query::new(&Filter {
+            stability,
+            max_latency,
+            ..Filter::default()
+        })
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn bucket_key_from_info() {
+        let proxy = Proxy::new("127.0.0.1", "80", "高匿", "HTTPS").unwrap();
+        let mut info = _ProxyInfo::default();
+        assert_eq!(
+            BucketKey::new(&proxy, &info),
+            key(0, UNKNOWN_LATENCY_BUCKET)
+        );
+        info.success = 7;
+        info.failed = 3;
+        info.latency_ewma = Some(100.0);
+        assert_eq!(BucketKey::new(&proxy, &info), key(7, 1));
+        info.success = 10;
+        info.failed = 0;
+        info.latency_ewma = Some(10_000.0);
+        assert_eq!(BucketKey::new(&proxy, &info), key(10, 7));
+    }
+
+    #[test]
+    fn stability_boundary() {
+        assert_eq!(key(5, 0).matches(&query(Some(0.5), None)), Match::Full);
+        assert_eq!(key(4, 0).matches(&query(Some(0.5), None)), Match::None);
+        assert_eq!(key(5, 0).matches(&query(Some(0.55), None)), Match::Partial);
+        assert_eq!(key(10, 0).matches(&query(Some(1.0), None)), Match::Full);
+        assert_eq!(key(0, 0).matches(&query(None, None)), Match::Full);
+    }
+
+    #[test]
+    fn latency_boundary() {
+        // 第一个区间是 [0, 100)
+        assert_eq!(key(10, 0).matches(&query(None, Some(100))), Match::Full);
+        assert_eq!(key(10, 0).matches(&query(None, Some(99))), Match::Partial);
+        // 第二个区间是 [100, 200)
+        assert_eq!(key(10, 1).matches(&query(None, Some(99))), Match::None);
+        assert_eq!(key(10, 1).matches(&query(None, Some(100))), Match::Partial);
+        assert_eq!(key(10, 1).matches(&query(None, Some(200))), Match::Full);
+        // 最后一个区间没有上界
+        assert_eq!(
+            key(10, 7).matches(&query(None, Some(u32::MAX))),
+            Match::Partial
+        );
+        assert_eq!(
+            key(10, UNKNOWN_LATENCY_BUCKET).matches(&query(None, Some(u32::MAX))),
+            Match::None
+        );
+    }
+
+    #[test]
+    fn choose_from_buckets() {
+        let mut rng = thread_rng();
+        let mut index = AttrIndex::default();
+        // 空索引
+        assert_eq!(index.choose(&mut rng, &query(None, None), |_| true), None);
+
+        index.insert(addr(1), key(9, 0));
+        index.insert(addr(2), key(9, 0));
+        index.insert(addr(3), key(5, 0));
+        index.insert(addr(4), key(5, 0));
+        index.insert(addr(5), key(1, 0));
+
+        // 稳定率 >= 0.55: 9 号桶完全符合, 5 号桶需要逐个检查, 1 号桶不符合
+        let query = query(Some(0.55), None);
+        let check = |key: &SocketAddr| *key == addr(3);
+        let mut seen = HashSet::new();
+        for _ in 0..200 {
+            seen.insert(index.choose(&mut rng, &query, check).unwrap());
+        }
+        let expected = [addr(1), addr(2), addr(3)].iter().copied().collect();
+        assert_eq!(seen, expected);
+
+        // 只剩下部分符合的桶, 且都没通过检查
+        index.remove(&addr(1));
+        index.remove(&addr(2));
+        assert_eq!(index.choose(&mut rng, &query, |_| false), None);
+        assert!(!index.buckets.contains_key(&key(9, 0)));
     }
 }
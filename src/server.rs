@@ -1,39 +1,234 @@
+use crate::blacklist::BanRule;
+use crate::config::ARuntimeConfig;
+use crate::export::ExportFormat;
+use crate::import::{parse_proxies, Format};
 use crate::proxy_pool::*;
-use crate::spider::proxy::Proxy;
-use rocket::{get, routes, State};
+use crate::spider::proxy::{Protocol, Proxy};
+use failure::Error;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, content::Content, Responder, Response};
+use rocket::{catch, catchers, get, post, routes, Data, Outcome, State};
 use rocket_contrib::json; // json! macro
 use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 
+/// /import 请求体的最大长度
+const IMPORT_LIMIT: u64 = 16 * 1024 * 1024;
+
 pub struct MyState {
     /// 代理池
     proxy_pool: AProxyPool,
     /// 是否重载配置
     reload_flag: Arc<RwLock<bool>>,
-    /// 管理密码
-    password: Arc<RwLock<Option<String>>>,
+    /// 可以重载的配置
+    runtime_config: ARuntimeConfig,
 }
 
 impl MyState {
     pub fn new(
         proxy_pool: AProxyPool,
         reload_flag: Arc<RwLock<bool>>,
-        password: Arc<RwLock<Option<String>>>,
+        runtime_config: ARuntimeConfig,
     ) -> Self {
         Self {
             proxy_pool,
             reload_flag,
-            password,
+            runtime_config,
+        }
+    }
+
+    /// 验证管理密码, 还没有读到配置(没有设置密码)时一律不通过
+    fn check_password(&self, password: &Option<String>) -> bool {
+        match (&self.runtime_config.read().unwrap().password, password) {
+            (Some(expected), Some(password)) => expected == password,
+            _ => false,
+        }
+    }
+
+    /// 需要管理密码的接口, 密码错误时返回 401, 还没有设置密码时返回 503
+    fn authorize(&self, password: &Option<String>) -> ApiResult<()> {
+        if self.runtime_config.read().unwrap().password.is_none() {
+            return Err(ApiError::new(Status::ServiceUnavailable, "未设置管理密码"));
+        }
+        if self.check_password(password) {
+            Ok(())
+        } else {
+            Err(ApiError::new(Status::Unauthorized, "管理密码错误"))
+        }
+    }
+}
+
+/// API 的错误, 统一以 {"error": 描述, "code": 状态码} 的格式返回
+#[derive(Debug)]
+pub struct ApiError {
+    status: Status,
+    message: String,
+}
+
+type ApiResult<T> = Result<T, ApiError>;
+
+impl ApiError {
+    fn new<S: Into<String>>(status: Status, message: S) -> Self {
+        Self {
+            status,
+            message: message.into(),
         }
     }
+
+    /// 参数有误
+    fn bad_request<E: Into<Error>>(e: E) -> Self {
+        Self::new(Status::BadRequest, e.into().to_string())
+    }
+
+    /// 没有符合条件的结果
+    fn not_found<S: Into<String>>(message: S) -> Self {
+        Self::new(Status::NotFound, message)
+    }
+
+    /// 服务器内部错误, 如读写文件失败
+    fn internal<E: Into<Error>>(e: E) -> Self {
+        Self::new(Status::InternalServerError, e.into().to_string())
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = json!({
+            "error": self.message,
+            "code": self.status.code,
+        });
+        Response::build_from(body.respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+/// 按格式输出代理列表, 未验证密码时隐去代理的认证信息
+fn render(proxies: &[Proxy], authorized: bool, format: ExportFormat) -> Content<String> {
+    let content_type = ContentType::parse_flexible(format.content_type()).unwrap();
+    if authorized {
+        Content(content_type, format.render(proxies))
+    } else {
+        let proxies = proxies.iter().map(Proxy::redacted).collect::<Vec<_>>();
+        Content(content_type, format.render(&proxies))
+    }
+}
+
+/// 未验证密码时隐去代理的认证信息
+fn redact(proxy: Proxy, authorized: bool) -> Proxy {
+    if authorized {
+        proxy
+    } else {
+        proxy.redacted()
+    }
+}
+
+/// 解析可选的参数, 无法解析时返回 400
+/// 数字和布尔值的参数也先按字符串接收, 否则 rocket 会把解析失败的参数当作没有提供
+fn parse_param<T>(name: &str, value: Option<String>) -> ApiResult<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .map(|value| value.parse())
+        .transpose()
+        .map_err(|e| ApiError::new(Status::BadRequest, format!("参数 {} 无效: {}", name, e)))
+}
+
+/// 解析代理地址(ip:port), 允许带上认证信息
+fn parse_addr(addr: &str) -> ApiResult<SocketAddr> {
+    let addr = addr.rsplit('@').next().unwrap_or_default();
+    addr.parse()
+        .map_err(|_| ApiError::new(Status::BadRequest, format!("无效的地址: {}", addr)))
+}
+
+/// 从 ip/cidr/port 参数中解析封禁规则, 必须且只能提供其中一个
+fn parse_rule(ip: Option<String>, cidr: Option<String>, port: Option<u16>) -> ApiResult<BanRule> {
+    match (ip, cidr, port) {
+        (Some(ip), None, None) => ip
+            .parse()
+            .map(BanRule::Ip)
+            .map_err(|_| ApiError::new(Status::BadRequest, format!("无效的 IP: {}", ip))),
+        (None, Some(cidr), None) => cidr
+            .parse()
+            .map(BanRule::Network)
+            .map_err(|_| ApiError::new(Status::BadRequest, format!("无效的 IP 段: {}", cidr))),
+        (None, None, Some(port)) => Ok(BanRule::Port(port)),
+        _ => Err(ApiError::new(
+            Status::BadRequest,
+            "ip, cidr, port 必须且只能提供其中一个",
+        )),
+    }
+}
+
+/// 客户端反馈的代理使用结果
+#[derive(Deserialize)]
+struct Report {
+    /// 代理地址, ip:port
+    proxy: String,
+    /// 是否成功
+    success: bool,
+    /// 访问目标的延迟(ms), 只在成功时记录
+    latency: Option<u32>,
+    /// 配置中的验证目标名称
+    target: Option<String>,
+}
+
+/// judge 需要的请求信息
+struct JudgeInfo {
+    /// 对端 IP, 这里不能用 client_ip(), 否则会被 X-Real-IP 影响
+    origin: String,
+    /// 所有请求头, 同名的请求头用 ", " 连接
+    headers: HashMap<String, String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for JudgeInfo {
+    type Error = !;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let origin = request
+            .remote()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default();
+        let mut headers = HashMap::new();
+        for header in request.headers().iter() {
+            headers
+                .entry(header.name().to_string())
+                .and_modify(|value: &mut String| {
+                    value.push_str(", ");
+                    value.push_str(header.value());
+                })
+                .or_insert_with(|| header.value().to_owned());
+        }
+        Outcome::Success(JudgeInfo { origin, headers })
+    }
 }
 
 #[get("/")]
 fn index(_state: State<MyState>) -> JsonValue {
     json!({
-        "get?<ssl_type:str>&<anonymity:str>&<stability:f32>": "随机获取一个代理, 带参数请求速度较慢. 大量请求建议使用 get_all 在本地筛选",
-        "get_all?<ssl_type:str>&<anonymity:str>&<stability:f32>": "获取所有可用代理",
+        "get?<protocol:str>&<anonymity:str>&<stability:f32>&<max_latency:u32>&<target:str>&<country:str>&<asn:str>&<fast:bool>&<strategy:str>&<count:usize>&<strict:bool>&<session:str>&<ttl:u64>&<distinct_exit:bool>&<password:str>": "随机获取一个代理. strategy=best 及 target 和 fast 参数需要遍历代理列表, 速度较慢. max_latency 单位为 ms, target 为配置中的验证目标名称, country 为国家/地区代码, 如 CN, CN,HK, !CN(排除中国), asn 为自治系统号, 如 AS4134, 这两项需要配置 GeoIP 数据库. fast=true 时优先返回延迟低的代理, strategy 可选 uniform/weighted/best, 默认值见配置. 指定 count 时返回至多 count 个不重复的代理组成的数组, strict=true 时不足 count 个则返回 404. 指定 session 时同一个会话总是返回同一个代理, 代理被降级或删除时自动更换, 返回 {proxy, session, rotated, expires_at}, rotated 表示是否更换了代理. 会话在最后一次请求 ttl 秒后过期, 默认值见配置. ssl_type 是 protocol 的旧名称, 已弃用. distinct_exit=true 需要和 count 一起使用, 返回的代理出口 IP 各不相同. 提供管理密码才会返回代理的认证信息",
+        "get_all?<protocol:str>&<anonymity:str>&<stability:f32>&<max_latency:u32>&<target:str>&<country:str>&<asn:str>&<format:str>&<password:str>": "获取所有可用代理, 提供管理密码时才会返回代理的认证信息. format 可选 json/txt/csv/pac/proxychains, 默认 json",
+        "checkout?<protocol:str>&<anonymity:str>&<stability:f32>&<max_latency:u32>&<target:str>&<country:str>&<asn:str>&<ttl:u64>&<password:str>": "POST, 借出一个代理, 返回代理和租约 ID. 归还或 ttl 秒后到期前不会再借给其他人. 提供管理密码时才会返回代理的认证信息",
+        "release?<id:str>": "POST, 提前归还借出的代理",
+        "report": "POST, 反馈代理的使用结果, 格式为 {\"proxy\": \"ip:port\", \"success\": bool, \"latency\": ms, \"target\": \"验证目标名称\"}, latency 和 target 可选, target 不是配置中的验证目标时返回 400",
+        "delete?<addr:str>&<password:str>": "POST, 从代理池中删除指定代理(ip:port)",
+        "ban?<ip:str>&<cidr:str>&<port:u16>&<password:str>": "POST, 封禁 IP, IP 段或端口, 三者选一. 会删除代理池中符合条件的代理, 之后也不会再加入",
+        "unban?<ip:str>&<cidr:str>&<port:u16>&<password:str>": "POST, 解除封禁, 参数同 ban",
+        "blacklist?<password:str>": "获取黑名单",
+        "import?<format:str>&<protocol:str>&<source:str>&<password:str>": "POST, 导入请求体中的代理, 格式为 json/txt/csv, 不指定则自动检测. protocol 为没有写明协议时使用的协议, 默认 http. source 为来源标记, 默认 import. 请求体不能超过 16MB",
+        "get_exits?<shared:bool>&<password:str>": "按出口 IP 分组列出稳定代理, 出口 IP 需要配置 judge_url 才能验证出来. shared=true 时只返回多个代理共用的出口",
         "get_status": "获取代理池信息",
+        "get_latency?<addr:str>": "获取指定代理(ip:port)的延迟统计",
+        "judge": "返回请求者的 IP 和请求头, 用于验证代理匿名程度, 需在配置中开启",
     })
 }
 
@@ -49,71 +244,476 @@ fn get_status(state: State<MyState>) -> JsonValue {
     })
 }
 
-// TODO: 提前搞个类型转换
-#[get("/get?<ssl_type>&<anonymity>&<stability>")]
+#[get("/get_exits?<shared>&<password>")]
+fn get_exits(
+    state: State<MyState>,
+    shared: Option<String>,
+    password: Option<String>,
+) -> ApiResult<JsonValue> {
+    let authorized = state.check_password(&password);
+    let shared = parse_param("shared", shared)?.unwrap_or(false);
+    let groups = state
+        .proxy_pool
+        .clone()
+        .exit_groups()
+        .into_iter()
+        .filter(|(_, proxies)| !shared || proxies.len() > 1)
+        .map(|(exit_ip, proxies)| {
+            let proxies = if authorized {
+                proxies
+            } else {
+                proxies.iter().map(Proxy::redacted).collect()
+            };
+            json!({
+                "exit_ip": exit_ip,
+                "count": proxies.len(),
+                "proxies": proxies,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json!(groups))
+}
+
+#[get("/get_latency?<addr>")]
+fn get_latency(state: State<MyState>, addr: String) -> ApiResult<Json<LatencyStats>> {
+    let addr = parse_addr(&addr)?;
+    state
+        .proxy_pool
+        .clone()
+        .get_latency_stats(&addr)
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("代理不存在"))
+}
+
+#[get(
+    "/get?<protocol>&<ssl_type>&<anonymity>&<stability>&<max_latency>&<target>&<country>&<asn>&<fast>&<strategy>&<count>&<strict>&<session>&<ttl>&<distinct_exit>&<password>"
+)]
 fn get_single(
     state: State<MyState>,
+    protocol: Option<String>,
     ssl_type: Option<String>,
     anonymity: Option<String>,
-    stability: Option<f32>,
-) -> Json<Proxy> {
+    stability: Option<String>,
+    max_latency: Option<String>,
+    target: Option<String>,
+    country: Option<String>,
+    asn: Option<String>,
+    fast: Option<String>,
+    strategy: Option<String>,
+    count: Option<String>,
+    strict: Option<String>,
+    session: Option<String>,
+    ttl: Option<String>,
+    distinct_exit: Option<String>,
+    password: Option<String>,
+) -> ApiResult<JsonValue> {
     let proxy_pool = &state.proxy_pool;
+    let authorized = state.check_password(&password);
+    let fast = parse_param("fast", fast)?.unwrap_or(false);
+    let count = parse_param::<usize>("count", count)?;
+    let strict = parse_param("strict", strict)?.unwrap_or(false);
+    let ttl = parse_param::<u64>("ttl", ttl)?;
+    let score = state.runtime_config.read().unwrap().score.clone();
+    let strategy = parse_param("strategy", strategy)?.unwrap_or(score.strategy);
+    let filter = Filter::parse(
+        // ssl_type 是 protocol 的旧名称, 保留以兼容旧的客户端
+        protocol.or(ssl_type),
+        anonymity,
+        parse_param("stability", stability)?,
+        parse_param("max_latency", max_latency)?,
+        target,
+        country,
+        asn,
+    )
+    .map_err(ApiError::bad_request)?;
 
-    // 啥参数都没有, 直接调用 get_random, O(1) 时间复杂度
-    let proxy = if ssl_type.is_none() && anonymity.is_none() && stability.is_none() {
-        proxy_pool.clone().get_random().unwrap()
-    // 有参数的话, 使用 O(n) 复杂度的 select_random
-    } else {
-        proxy_pool
-            .clone()
-            .select_random(ssl_type, anonymity, stability)
-            .unwrap()
+    if count.is_some() && session.is_some() {
+        return Err(ApiError::new(
+            Status::BadRequest,
+            "count 和 session 不能同时使用",
+        ));
+    }
+    let distinct_exit = parse_param("distinct_exit", distinct_exit)?.unwrap_or(false);
+    if distinct_exit && count.is_none() {
+        return Err(ApiError::new(
+            Status::BadRequest,
+            "distinct_exit 需要和 count 一起使用",
+        ));
+    }
+
+    // 指定了 count 时返回数组, 即使 count=1
+    if let Some(count) = count {
+        if count == 0 {
+            return Err(ApiError::new(Status::BadRequest, "count 必须大于 0"));
+        }
+        let proxies =
+            proxy_pool
+                .clone()
+                .sample(filter, count, fast, strategy, &score, distinct_exit);
+        if strict && proxies.len() < count {
+            return Err(ApiError::not_found(format!(
+                "只有 {} 个符合条件的代理",
+                proxies.len()
+            )));
+        }
+        let proxies = proxies
+            .into_iter()
+            .map(|proxy| redact(proxy, authorized))
+            .collect::<Vec<_>>();
+        return Ok(json!(proxies));
+    }
+
+    let pick = |filter: Filter| {
+        // 啥参数都没有, 直接调用 get_random, O(1) 时间复杂度
+        if filter.is_empty() && !fast && strategy == Strategy::Uniform {
+            proxy_pool.clone().get_random()
+        // fast=true 时按延迟加权
+        } else if fast {
+            proxy_pool.clone().select_random(filter, fast)
+        // 按评分取代理, best 需要遍历所有符合条件的代理, weighted 大多数时候可以走属性索引
+        } else {
+            proxy_pool.clone().select_by_score(filter, strategy, &score)
+        }
     };
-    // None 会被序列化为 null, Some 会被忽略, 非常棒棒
-    Json(proxy)
+
+    // 指定了 session 时, 同一个会话在过期前总是返回同一个代理, 代理失效时才会更换
+    if let Some(session) = session {
+        if session.is_empty() {
+            return Err(ApiError::new(Status::BadRequest, "session 不能为空"));
+        }
+        let config = state.runtime_config.read().unwrap().session.clone();
+        let ttl = ttl.unwrap_or(config.default_ttl).min(config.max_ttl);
+        let (proxy, rotated) = proxy_pool
+            .clone()
+            .sticky(&session, ttl, config.max_sessions, filter, pick)
+            .ok_or_else(|| ApiError::not_found("没有符合条件的代理"))?;
+        return Ok(json!({
+            "proxy": redact(proxy, authorized),
+            "session": session,
+            "rotated": rotated,
+            "expires_at": unix_now() + ttl,
+        }));
+    }
+
+    pick(filter)
+        .map(|proxy| json!(redact(proxy, authorized)))
+        .ok_or_else(|| ApiError::not_found("没有符合条件的代理"))
 }
 
 // 此处还是使用了 String 而不是 Json<Vec<Proxy>>
 // 因为 get_stable 和 select 的返回值类型不同实在难以处理 (除非 clone...
-#[get("/get_all?<ssl_type>&<anonymity>&<stability>")]
+// 再加上还要支持 JSON 以外的格式
+#[get("/get_all?<protocol>&<ssl_type>&<anonymity>&<stability>&<max_latency>&<target>&<country>&<asn>&<format>&<password>")]
 fn get_all(
     state: State<MyState>,
+    protocol: Option<String>,
     ssl_type: Option<String>,
     anonymity: Option<String>,
-    stability: Option<f32>,
-) -> String {
+    stability: Option<String>,
+    max_latency: Option<String>,
+    target: Option<String>,
+    country: Option<String>,
+    asn: Option<String>,
+    format: Option<String>,
+    password: Option<String>,
+) -> ApiResult<Content<String>> {
     let proxy_pool = &state.proxy_pool;
+    let authorized = state.check_password(&password);
+    let format = parse_param("format", format)?.unwrap_or(ExportFormat::Json);
+    let filter = Filter::parse(
+        protocol.or(ssl_type),
+        anonymity,
+        parse_param("stability", stability)?,
+        parse_param("max_latency", max_latency)?,
+        target,
+        country,
+        asn,
+    )
+    .map_err(ApiError::bad_request)?;
     // get_stable 返回 &Vec<T>, select 返回 Vec<&T>, 所以这个地方无法简化成 get_single 的逻辑
-    if ssl_type.is_none() && anonymity.is_none() && stability.is_none() {
+    // 没有符合条件的代理时返回空列表, 而不是 404
+    if filter.is_empty() {
         let proxy = &*proxy_pool.get_stable();
-        serde_json::to_string_pretty(proxy).unwrap()
+        Ok(render(proxy, authorized, format))
     } else {
-        let proxy = proxy_pool.clone().select(ssl_type, anonymity, stability);
-        serde_json::to_string_pretty(&proxy).unwrap()
+        let proxy = proxy_pool.clone().select(filter);
+        Ok(render(&proxy, authorized, format))
     }
 }
 
-// TODO: del api
-// 其实并不想增加这个 API, 感觉没啥用...还增加复杂度
-
-#[get("/reload?<password>")]
-fn reload(state: State<MyState>, password: Option<String>) -> JsonValue {
-    if *state.password.read().unwrap() == password {
-        *state.reload_flag.write().unwrap() = true;
-        json!({
-            "success": true
+#[post(
+    "/checkout?<protocol>&<anonymity>&<stability>&<max_latency>&<target>&<country>&<asn>&<ttl>&<password>"
+)]
+fn checkout(
+    state: State<MyState>,
+    protocol: Option<String>,
+    anonymity: Option<String>,
+    stability: Option<String>,
+    max_latency: Option<String>,
+    target: Option<String>,
+    country: Option<String>,
+    asn: Option<String>,
+    ttl: Option<String>,
+    password: Option<String>,
+) -> ApiResult<Json<Lease>> {
+    let authorized = state.check_password(&password);
+    let config = state.runtime_config.read().unwrap().lease.clone();
+    let ttl = parse_param("ttl", ttl)?
+        .unwrap_or(config.default_ttl)
+        .min(config.max_ttl);
+    let filter = Filter::parse(
+        protocol,
+        anonymity,
+        parse_param("stability", stability)?,
+        parse_param("max_latency", max_latency)?,
+        target,
+        country,
+        asn,
+    )
+    .map_err(ApiError::bad_request)?;
+    state
+        .proxy_pool
+        .clone()
+        .checkout(filter, ttl, config.max_per_proxy)
+        .map(|lease| {
+            Json(Lease {
+                proxy: redact(lease.proxy, authorized),
+                ..lease
+            })
         })
+        .ok_or_else(|| ApiError::not_found("没有可以借出的代理"))
+}
+
+#[post("/release?<id>")]
+fn release(state: State<MyState>, id: String) -> ApiResult<JsonValue> {
+    if state.proxy_pool.clone().release(&id) {
+        Ok(json!({ "success": true }))
     } else {
-        json!({
-            "success": false
-        })
+        Err(ApiError::not_found("租约不存在或已到期"))
+    }
+}
+
+#[post("/report", format = "json", data = "<report>")]
+fn report(state: State<MyState>, report: Json<Report>) -> ApiResult<JsonValue> {
+    let proxy_pool = &state.proxy_pool;
+    let addr = parse_addr(&report.proxy)?;
+    let (config, targets) = {
+        let runtime_config = state.runtime_config.read().unwrap();
+        (
+            runtime_config.report.clone(),
+            runtime_config.targets.clone(),
+        )
+    };
+    // 只接受配置中的验证目标, 否则任何人都能让 proxies.json 无限增长
+    if let Some(target) = &report.target {
+        if !targets.contains(target) {
+            return Err(ApiError::new(
+                Status::BadRequest,
+                format!("未知的验证目标: {}", target),
+            ));
+        }
+    }
+    let proxy = proxy_pool
+        .clone()
+        .get_proxy(&addr)
+        .ok_or_else(|| ApiError::not_found("代理不存在"))?;
+
+    if report.success {
+        for _ in 0..config.success_weight {
+            proxy_pool.clone().inc_success_cnt(&proxy);
+        }
+        if let Some(latency) = report.latency {
+            proxy_pool.clone().record_latency(&proxy, latency);
+        }
+    } else {
+        for _ in 0..config.fail_weight {
+            proxy_pool.clone().inc_failed_cnt(&proxy);
+        }
+        // 尽快重新验证, 该降级的降级, 该移除的移除
+        proxy_pool.clone().check_soon(&proxy);
+    }
+    if let Some(target) = &report.target {
+        proxy_pool
+            .clone()
+            .record_target(&proxy, target, report.success);
+    }
+    Ok(json!({ "success": true }))
+}
+
+// 格式与 httpbin 的 /get 相同, 未开启时返回 404
+#[get("/judge")]
+fn judge(state: State<MyState>, info: JudgeInfo) -> ApiResult<JsonValue> {
+    if !state.runtime_config.read().unwrap().judge {
+        return Err(ApiError::not_found("judge 未开启"));
+    }
+    Ok(json!({
+        "origin": info.origin,
+        "headers": info.headers,
+    }))
+}
+
+#[post("/delete?<addr>&<password>")]
+fn delete(state: State<MyState>, addr: String, password: Option<String>) -> ApiResult<JsonValue> {
+    state.authorize(&password)?;
+    let addr = parse_addr(&addr)?;
+    if state.proxy_pool.clone().remove(&addr) {
+        Ok(json!({ "success": true }))
+    } else {
+        Err(ApiError::not_found("代理不存在"))
+    }
+}
+
+#[post("/ban?<ip>&<cidr>&<port>&<password>")]
+fn ban(
+    state: State<MyState>,
+    ip: Option<String>,
+    cidr: Option<String>,
+    port: Option<u16>,
+    password: Option<String>,
+) -> ApiResult<JsonValue> {
+    state.authorize(&password)?;
+    let rule = parse_rule(ip, cidr, port)?;
+    let removed = state
+        .proxy_pool
+        .clone()
+        .ban(rule)
+        .map_err(ApiError::internal)?;
+    Ok(json!({
+        "success": true,
+        "removed": removed,
+    }))
+}
+
+#[post("/unban?<ip>&<cidr>&<port>&<password>")]
+fn unban(
+    state: State<MyState>,
+    ip: Option<String>,
+    cidr: Option<String>,
+    port: Option<u16>,
+    password: Option<String>,
+) -> ApiResult<JsonValue> {
+    state.authorize(&password)?;
+    let rule = parse_rule(ip, cidr, port)?;
+    let existed = state
+        .proxy_pool
+        .clone()
+        .unban(rule)
+        .map_err(ApiError::internal)?;
+    if existed {
+        Ok(json!({ "success": true }))
+    } else {
+        Err(ApiError::not_found("黑名单中没有这条规则"))
     }
 }
 
+#[post("/import?<format>&<protocol>&<source>&<password>", data = "<data>")]
+fn import(
+    state: State<MyState>,
+    format: Option<String>,
+    protocol: Option<String>,
+    source: Option<String>,
+    password: Option<String>,
+    data: Data,
+) -> ApiResult<JsonValue> {
+    state.authorize(&password)?;
+    let format = parse_param::<Format>(format)?;
+    let protocol = protocol.as_deref().unwrap_or("http");
+    Protocol::parse_strict(protocol).map_err(ApiError::bad_request)?;
+    // release 模式下 Rocket 0.4 不能直接用 String 接收请求体, 而且需要限制大小
+    // 多读一个字节, 用来判断是否超过了限制
+    let mut body = String::new();
+    data.open()
+        .take(IMPORT_LIMIT + 1)
+        .read_to_string(&mut body)
+        .map_err(ApiError::bad_request)?;
+    if body.len() as u64 > IMPORT_LIMIT {
+        return Err(ApiError::new(
+            Status::PayloadTooLarge,
+            format!("请求体不能超过 {} 字节", IMPORT_LIMIT),
+        ));
+    }
+    let (proxies, invalid) =
+        parse_proxies(&body, format, protocol).map_err(ApiError::bad_request)?;
+    let total = proxies.len();
+    // 和爬取到的代理一样, 先进入不稳定列表等待验证
+    let added = state
+        .proxy_pool
+        .clone()
+        .extend_unstable(proxies, source.as_deref().unwrap_or("import"));
+    Ok(json!({
+        "success": true,
+        "added": added,
+        "skipped": total - added,
+        "invalid": invalid,
+    }))
+}
+
+#[get("/blacklist?<password>")]
+fn blacklist(state: State<MyState>, password: Option<String>) -> ApiResult<JsonValue> {
+    state.authorize(&password)?;
+    Ok(json!(&*state.proxy_pool.get_blacklist()))
+}
+
+#[get("/reload?<password>")]
+fn reload(state: State<MyState>, password: Option<String>) -> ApiResult<JsonValue> {
+    state.authorize(&password)?;
+    *state.reload_flag.write().unwrap() = true;
+    Ok(json!({
+        "success": true
+    }))
+}
+
+// 路由没有匹配, 请求体格式错误等情况下, 也返回和 ApiError 相同格式的错误
+#[catch(400)]
+fn bad_request() -> JsonValue {
+    json!({ "error": "请求格式错误", "code": 400 })
+}
+
+#[catch(404)]
+fn not_found() -> JsonValue {
+    json!({ "error": "接口不存在", "code": 404 })
+}
+
+#[catch(422)]
+fn unprocessable_entity() -> JsonValue {
+    json!({ "error": "无法解析请求体", "code": 422 })
+}
+
+#[catch(500)]
+fn internal_error() -> JsonValue {
+    json!({ "error": "服务器内部错误", "code": 500 })
+}
+
 /// 火箭发射!
 pub fn launch_rocket(state: MyState) {
     rocket::ignite()
-        .mount("/", routes![index, get_status, get_single, get_all, reload])
+        .mount(
+            "/",
+            routes![
+                index,
+                get_status,
+                get_latency,
+                get_exits,
+                get_single,
+                get_all,
+                checkout,
+                release,
+                report,
+                delete,
+                ban,
+                unban,
+                blacklist,
+                import,
+                judge,
+                reload
+            ],
+        )
+        .register(catchers![
+            bad_request,
+            not_found,
+            unprocessable_entity,
+            internal_error
+        ])
         .manage(state)
         .launch();
 }
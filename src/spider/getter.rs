@@ -4,6 +4,11 @@ use itertools::izip;
 use log::{error, info};
 use regex::Regex;
 
+/// 日志中去掉 IP 里可能带有的认证信息
+fn redact_ip(ip: &str) -> &str {
+    ip.rsplit('@').next().unwrap_or(ip)
+}
+
 /// 处理比较规整的代理网站
 pub fn table_getter<T: AsRef<str>>(
     // 网站名称, 用于日志
@@ -14,7 +19,7 @@ pub fn table_getter<T: AsRef<str>>(
     xpath_1: &str,
     // 提取列
     xpath_2: &str,
-    // ip, 端口, 匿名性, 类型 所在的位置
+    // ip, 端口, 匿名性, 协议 所在的位置
     info_pos: &[usize; 4],
 ) -> SpiderResult<Vec<Proxy>> {
     let mut ret = vec![];
@@ -45,18 +50,18 @@ pub fn table_getter<T: AsRef<str>>(
                 continue;
             }
 
-            let (ip, port, anonymity, ssl_type) = (
+            let (ip, port, anonymity, protocol) = (
                 &info[info_pos[0]],
                 &info[info_pos[1]],
                 &info[info_pos[2]],
                 &info[info_pos[3]],
             );
 
-            if let Ok(proxy) = Proxy::new(ip, port, anonymity, ssl_type) {
-                info!("{}: [{}, {}, {}, {}]", name, ip, port, anonymity, ssl_type);
+            if let Ok(proxy) = Proxy::new(ip, port, anonymity, protocol) {
+                info!("{}: [{}, {}, {}]", name, proxy, anonymity, protocol);
                 ret.push(proxy);
             } else {
-                error!("BAD IP from {}: [{}, {}]", name, ip, port);
+                error!("BAD IP from {}: [{}, {}]", name, redact_ip(ip), port);
             }
         }
     }
@@ -75,34 +80,34 @@ pub fn regex_getter<T: AsRef<str>>(
     re_port: &str,
     // 提取匿名程度
     re_anonymity: &str,
-    // 提取 SSL 类型
-    re_ssl_type: &str,
+    // 提取协议类型
+    re_protocol: &str,
 ) -> SpiderResult<Vec<Proxy>> {
     let re_ip = Regex::new(re_ip)?;
     let re_port = Regex::new(re_port)?;
     let re_anonymity = Regex::new(re_anonymity)?;
-    let re_ssl_type = Regex::new(re_ssl_type)?;
+    let re_protocol = Regex::new(re_protocol)?;
 
     let mut ret = vec![];
     for url in url_list {
         let html = get_html(url.as_ref())?;
 
-        for (ip, port, anonymity, ssl_type) in izip!(
+        for (ip, port, anonymity, protocol) in izip!(
             re_ip.captures_iter(&html),
             re_port.captures_iter(&html),
             re_anonymity.captures_iter(&html),
-            re_ssl_type.captures_iter(&html)
+            re_protocol.captures_iter(&html)
         ) {
             let ip = &ip[0];
             let port = &port[0];
             let anonymity = &anonymity[0];
-            let ssl_type = &ssl_type[0];
+            let protocol = &protocol[0];
 
-            if let Ok(proxy) = Proxy::new(ip, port, anonymity, ssl_type) {
-                info!("{}: [{}, {}, {}, {}]", name, ip, port, anonymity, ssl_type);
+            if let Ok(proxy) = Proxy::new(ip, port, anonymity, protocol) {
+                info!("{}: [{}, {}, {}]", name, proxy, anonymity, protocol);
                 ret.push(proxy);
             } else {
-                error!("BAD IP from {}: [{}, {}]", name, ip, port);
+                error!("BAD IP from {}: [{}, {}]", name, redact_ip(ip), port);
             }
         }
     }
@@ -1,12 +1,13 @@
-use failure::Error;
+use failure::{format_err, Error};
 use serde::{Deserialize, Serialize};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
 pub type SpiderResult<T> = Result<T, Error>;
 
 /// 匿名程度
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum AnonymityLevel {
     /// 透明
     Transparent,
@@ -31,45 +32,144 @@ impl FromStr for AnonymityLevel {
     }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
-pub enum SslType {
+impl AnonymityLevel {
+    /// 严格解析, 用于 API 参数等用户输入, 无法识别时返回错误
+    pub fn parse_strict(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "transparent" | "透明" => Ok(AnonymityLevel::Transparent),
+            "anonymous" | "匿名" | "普匿" => Ok(AnonymityLevel::Anonymous),
+            "elite" | "高匿" => Ok(AnonymityLevel::Elite),
+            _ => Err(format_err!("未知的匿名程度: {}", s)),
+        }
+    }
+
+    /// parse_strict 能识别的名称, 导出时使用
+    pub fn name(self) -> &'static str {
+        match self {
+            AnonymityLevel::Transparent => "transparent",
+            AnonymityLevel::Anonymous => "anonymous",
+            AnonymityLevel::Elite => "elite",
+        }
+    }
+}
+
+/// 代理协议
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Protocol {
+    /// 普通 HTTP 代理
     HTTP,
+    /// 支持 CONNECT 的 HTTP 代理
     HTTPS,
+    /// SOCKS4(a)
+    SOCKS4,
+    /// SOCKS5
+    SOCKS5,
 }
 
-impl FromStr for SslType {
+impl FromStr for Protocol {
     type Err = !;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(if s.contains("HTTPS") || s.contains("https") {
-            SslType::HTTPS
+        // 各网站大小写不统一, 统一转成小写再判断
+        let s = s.to_lowercase();
+        Ok(if s.contains("socks5") {
+            Protocol::SOCKS5
+        } else if s.contains("socks4") {
+            Protocol::SOCKS4
+        } else if s.contains("https") {
+            Protocol::HTTPS
         } else {
             // 默认 HTTP
-            SslType::HTTP
+            Protocol::HTTP
         })
     }
 }
 
+impl Protocol {
+    /// 严格解析, 用于 API 参数等用户输入, 无法识别时返回错误
+    pub fn parse_strict(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "http" => Ok(Protocol::HTTP),
+            "https" => Ok(Protocol::HTTPS),
+            "socks4" => Ok(Protocol::SOCKS4),
+            "socks5" => Ok(Protocol::SOCKS5),
+            _ => Err(format_err!("未知的协议: {}", s)),
+        }
+    }
+
+    /// parse_strict 能识别的名称, 导出时使用
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::HTTP => "http",
+            Protocol::HTTPS => "https",
+            Protocol::SOCKS4 => "socks4",
+            Protocol::SOCKS5 => "socks5",
+        }
+    }
+}
+
+/// 代理的认证信息
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Proxy {
-    ip: Ipv4Addr,
+    ip: IpAddr,
     port: u16,
     anonymity: AnonymityLevel,
-    ssl_type: SslType,
+    // 兼容旧版本的 proxies.json
+    #[serde(alias = "ssl_type")]
+    protocol: Protocol,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    credentials: Option<Credentials>,
 }
 
 impl Proxy {
-    pub fn new(ip: &str, port: &str, anonymity: &str, ssl_type: &str) -> Result<Self, Error> {
+    pub fn new(ip: &str, port: &str, anonymity: &str, protocol: &str) -> Result<Self, Error> {
+        // 付费代理一般是 user:pass@host:port 的形式
+        let (credentials, ip) = match ip.rfind('@') {
+            Some(pos) => {
+                let mut auth = ip[..pos].splitn(2, ':');
+                let credentials = Credentials {
+                    username: auth.next().unwrap_or_default().to_owned(),
+                    password: auth.next().unwrap_or_default().to_owned(),
+                };
+                (Some(credentials), &ip[pos + 1..])
+            }
+            None => (None, ip),
+        };
+        // 有的网站会把 IP 和端口写在一起, 如 1.2.3.4:80 或 [::1]:8080
+        let (ip, port) = match ip.parse::<SocketAddr>() {
+            Ok(addr) => (addr.ip(), addr.port()),
+            // IPv6 地址可能带有方括号
+            Err(_) => (
+                ip.trim_matches(|c| c == '[' || c == ']').parse()?,
+                port.parse()?,
+            ),
+        };
         Ok(Self {
-            ip: ip.parse()?,
-            port: port.parse()?,
+            ip,
+            port,
             anonymity: anonymity.parse()?,
-            ssl_type: ssl_type.parse()?,
+            protocol: protocol.parse()?,
+            credentials,
         })
     }
 
+    /// 隐去密码, 用于对外展示
+    pub fn redacted(&self) -> Self {
+        let mut proxy = self.clone();
+        if let Some(credentials) = &mut proxy.credentials {
+            credentials.password = "******".to_owned();
+        }
+        proxy
+    }
+
     #[inline]
-    pub fn ip(&self) -> Ipv4Addr {
+    pub fn ip(&self) -> IpAddr {
         self.ip
     }
 
@@ -84,12 +184,42 @@ impl Proxy {
     }
 
     #[inline]
-    pub fn ssl_type(&self) -> SslType {
-        self.ssl_type
+    pub fn set_anonymity(&mut self, anonymity: AnonymityLevel) {
+        self.anonymity = anonymity;
     }
 
     #[inline]
-    pub fn get_key(&self) -> SocketAddrV4 {
-        SocketAddrV4::new(self.ip, self.port)
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    #[inline]
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    #[inline]
+    pub fn get_key(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
+/// 代理池以 get_key() 去重, 这里也只比较地址
+/// 否则验证线程更新了匿名程度之后, 手上旧的 Proxy 就无法在列表中找到了
+impl PartialEq for Proxy {
+    fn eq(&self, other: &Self) -> bool {
+        self.get_key() == other.get_key()
+    }
+}
+
+impl Eq for Proxy {}
+
+/// 用于日志输出, 不会输出密码
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.credentials {
+            Some(credentials) => write!(f, "{}:******@{}", credentials.username, self.get_key()),
+            None => write!(f, "{}", self.get_key()),
+        }
     }
 }
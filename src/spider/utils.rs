@@ -1,45 +1,82 @@
 use super::proxy::*;
 use super::user_agent;
 use crate::config::*;
+use crate::handshake::socks4_connect;
 use failure::format_err;
+use lazy_static::lazy_static;
 use libxml::{
     parser::Parser,
     tree::{document::Document, node::Node},
     xpath::Context,
 };
 use log::*;
-use reqwest::{header, Client};
-use std::sync::Arc;
-use std::time::Duration;
+use native_tls::TlsConnector;
+use reqwest::{blocking, header, Client, Url};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+lazy_static! {
+    /// 向本地接口获取代理时使用的管理密码, 没有密码时接口不会返回代理的认证信息
+    static ref API_PASSWORD: RwLock<String> = RwLock::new(String::new());
+    /// 所有验证共用一份 TLS 配置
+    /// 每次验证都要用新的代理构建 Client, 共用 TLS 配置以免每次都重新加载根证书
+    static ref TLS: TlsConnector = TlsConnector::new().expect("无法初始化 TLS");
+}
+
+/// 设置获取代理时使用的管理密码, 每次读取配置后调用
+pub fn set_api_password(password: &str) {
+    *API_PASSWORD.write().unwrap() = password.to_owned();
+}
 
 /// 来一份代理
-fn get_proxy(ssl_type: &str) -> SpiderResult<reqwest::Proxy> {
-    let mut res = reqwest::get(&format!(
-        "http://localhost:8000/get?ssl_type={}&anonymity=高匿",
-        ssl_type
-    ))?;
+fn get_proxy(protocol: &str) -> SpiderResult<reqwest::Proxy> {
+    let url = Url::parse_with_params(
+        "http://localhost:8000/get",
+        &[
+            ("protocol", protocol),
+            ("anonymity", "高匿"),
+            ("password", &API_PASSWORD.read().unwrap()),
+        ],
+    )?;
+    let res = blocking::get(url)?;
     let proxy: Proxy = serde_json::from_str(&res.text()?)?;
-    info!("获取代理: {}:{}", proxy.ip(), proxy.port());
-    Ok(reqwest::Proxy::all(&format!(
-        "http://{}:{}",
-        proxy.ip(),
-        proxy.port()
-    ))?)
+    info!("获取代理: {}", proxy);
+    to_reqwest_proxy(&proxy)
+}
+
+/// 转换为 reqwest 的代理, reqwest 不支持 SOCKS4
+fn to_reqwest_proxy(proxy: &Proxy) -> SpiderResult<reqwest::Proxy> {
+    let scheme = match proxy.protocol() {
+        Protocol::HTTP | Protocol::HTTPS => "http",
+        Protocol::SOCKS5 => "socks5",
+        Protocol::SOCKS4 => return Err(format_err!("reqwest 不支持 SOCKS4 代理")),
+    };
+    // SocketAddr 的 Display 会给 IPv6 地址加上方括号
+    let mut ret = reqwest::Proxy::all(&format!("{}://{}", scheme, proxy.get_key()))?;
+    if let Some(credentials) = proxy.credentials() {
+        ret = ret.basic_auth(&credentials.username, &credentials.password);
+    }
+    Ok(ret)
 }
 
 /// 获取网页
 pub fn get_html<S: AsRef<str>>(url: S) -> SpiderResult<String> {
     for i in 0..5 {
-        let mut client = Client::builder().timeout(Duration::from_secs(20));
+        let mut client = blocking::Client::builder().timeout(Duration::from_secs(20));
         // 第一次不使用代理
         if i > 0 {
             // 根据 URL 选择代理类型
-            let ssl_type = if url.as_ref().contains("https") {
+            let protocol = if url.as_ref().contains("https") {
                 "HTTPS"
             } else {
                 "HTTP"
             };
-            match get_proxy(ssl_type) {
+            match get_proxy(protocol) {
                 Ok(proxy) => client = client.proxy(proxy),
                 Err(err) => error!("获取代理失败: {}", err),
             }
@@ -61,7 +98,7 @@ pub fn get_html<S: AsRef<str>>(url: S) -> SpiderResult<String> {
             .send();
 
         match res {
-            Ok(mut res) => {
+            Ok(res) => {
                 if res.status().is_success() {
                     return Ok(res.text()?);
                 } else {
@@ -96,23 +133,288 @@ pub fn get_xpath(
     Ok((document, eval_xpath))
 }
 
-/// 检测代理可用性
-#[inline]
-pub fn check_proxy(proxy: &Proxy, config: &Arc<CheckerConfig>) -> bool {
-    let ssl_type = proxy.ssl_type();
-    let proxy = reqwest::Proxy::all(&format!("http://{}:{}", proxy.ip(), proxy.port())).unwrap();
-    let client = Client::builder()
-        .timeout(Duration::from_secs(config.timeout))
-        .proxy(proxy)
-        .build()
-        .expect("无法构建 Client");
+/// 没有设置 max_size 时最多读取这么多响应体(bytes)
+const BODY_LIMIT: u64 = 1024 * 1024;
+
+/// 检测代理可用性, 可用时返回延迟(ms)
+pub async fn check_proxy(proxy: &Proxy, config: &CheckerConfig) -> Option<u32> {
     // httpbin 在国外, 应该不能代表国内访问速度
-    let res = match ssl_type {
-        SslType::HTTPS => client.head(&config.url_https).send(),
-        SslType::HTTP => client.head(&config.url_http).send(),
+    // HTTPS 代理访问 https 的 URL, reqwest 会走 CONNECT 隧道
+    let (url, expect) = match proxy.protocol() {
+        Protocol::HTTPS => (&config.url_https, &config.expect_https),
+        _ => (&config.url_http, &config.expect_http),
+    };
+    check_url(proxy, url, expect, config.timeout).await
+}
+
+/// 构建通过指定代理访问的 Client
+fn build_client(proxy: &Proxy, timeout: u64) -> SpiderResult<Client> {
+    Ok(Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .proxy(to_reqwest_proxy(proxy)?)
+        .use_preconfigured_tls(TLS.clone())
+        // 每个 Client 只用一次, 不需要连接池
+        .pool_max_idle_per_host(0)
+        .build()?)
+}
+
+/// 通过代理访问指定 URL, 响应符合要求时返回延迟(ms)
+pub async fn check_url(proxy: &Proxy, url: &str, expect: &Expect, timeout: u64) -> Option<u32> {
+    // SOCKS4 只能自己握手
+    if proxy.protocol() == Protocol::SOCKS4 {
+        let start = Instant::now();
+        let res = tokio::time::timeout(
+            Duration::from_secs(timeout),
+            check_socks4(proxy, url, expect),
+        )
+        .await;
+        return match res {
+            Ok(Ok(true)) => Some(start.elapsed().as_millis() as u32),
+            _ => None,
+        };
+    }
+    let client = match build_client(proxy, timeout) {
+        Ok(client) => client,
+        // reqwest 不支持的代理算作验证失败, 不能让验证任务 panic
+        Err(e) => {
+            info!("无法构建 Client: {}, {}", proxy, e);
+            return None;
+        }
+    };
+    // 从发出请求开始计时, 不计入构建 Client 的时间
+    let start = Instant::now();
+    // 需要检查响应体时才使用 GET
+    let res = if expect.need_body() {
+        client.get(url).send().await
+    } else {
+        client.head(url).send().await
+    };
+    let mut res = res.ok()?;
+    // 延迟只算到收到响应头为止
+    let latency = start.elapsed().as_millis() as u32;
+
+    let status = res.status().as_u16();
+    let headers = res
+        .headers()
+        .keys()
+        .map(|name| name.as_str().to_owned())
+        .collect::<Vec<_>>();
+    let body = if expect.need_body() {
+        let limit = expect.max_size.map_or(BODY_LIMIT, |size| size + 1) as usize;
+        let mut body = vec![];
+        while let Some(chunk) = res.chunk().await.ok()? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= limit {
+                body.truncate(limit);
+                break;
+            }
+        }
+        Some(body)
+    } else {
+        None
     };
-    match res {
-        Ok(r) => r.status().is_success(),
-        Err(_e) => false,
+    if verify_response(expect, status, &headers, body.as_deref()) {
+        Some(latency)
+    } else {
+        None
     }
 }
+
+/// 检查响应是否符合要求
+/// headers 为响应头名称, body 为 None 表示没有读取响应体
+fn verify_response(expect: &Expect, status: u16, headers: &[String], body: Option<&[u8]>) -> bool {
+    let status_ok = match expect.status {
+        Some(expected) => status == expected,
+        None => (200..300).contains(&status),
+    };
+    if !status_ok {
+        return false;
+    }
+    let headers_ok = expect.headers.iter().all(|name| {
+        headers
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(name))
+    });
+    if !headers_ok {
+        return false;
+    }
+    if let Some(body) = body {
+        if expect
+            .max_size
+            .map_or(false, |size| body.len() as u64 > size)
+        {
+            return false;
+        }
+        let body = String::from_utf8_lossy(body);
+        if let Some(contains) = &expect.contains {
+            if !body.contains(contains.as_str()) {
+                return false;
+            }
+        }
+        if let Some(regex) = &expect.regex {
+            if !regex.is_match(&body) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// 完成 SOCKS4 握手, 然后通过隧道手动发一个 HTTP 请求
+/// 超时由调用者处理
+async fn check_socks4(proxy: &Proxy, url: &str, expect: &Expect) -> SpiderResult<bool> {
+    let url = Url::parse(url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| format_err!("无效的 URL: {}", url))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut stream = TcpStream::connect(proxy.get_key()).await?;
+    if socks4_connect(&mut stream, proxy, host, port)
+        .await
+        .is_err()
+    {
+        return Ok(false);
+    }
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    };
+    // 使用 HTTP/1.0, 省得处理 chunked
+    let request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        if expect.need_body() { "GET" } else { "HEAD" },
+        path,
+        host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![];
+    // 多读 16K 给响应头
+    stream
+        .take(expect.max_size.unwrap_or(BODY_LIMIT) + 16 * 1024)
+        .read_to_end(&mut response)
+        .await?;
+
+    // 拆分响应头和响应体
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| format_err!("无效的响应"))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let mut lines = head.lines();
+    // 状态行形如 "HTTP/1.1 200 OK"
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or_else(|| format_err!("无效的状态行"))?
+        .parse::<u16>()?;
+    let headers = lines
+        .filter_map(|line| line.split(':').next())
+        .map(|name| name.trim().to_owned())
+        .collect::<Vec<_>>();
+    let body = if expect.need_body() {
+        Some(&response[split + 4..])
+    } else {
+        None
+    };
+    Ok(verify_response(expect, status, &headers, body))
+}
+
+/// 会泄露真实 IP 的请求头
+const LEAK_HEADERS: &[&str] = &["X-Forwarded-For", "X-Real-Ip", "Forwarded", "Client-Ip"];
+
+/// 会暴露使用了代理的请求头
+const PROXY_HEADERS: &[&str] = &[
+    "Via",
+    "X-Forwarded-For",
+    "Forwarded",
+    "Proxy-Connection",
+    "X-Proxy-Id",
+];
+
+/// 取出 origin 或 X-Forwarded-For 等请求头中的所有 IP
+/// 多个地址用 , 分隔, 也兼容 Forwarded 的 for="[::1]:80" 和带端口的写法, 无法解析的部分会被忽略
+fn parse_ips(value: &str) -> impl Iterator<Item = IpAddr> + '_ {
+    value.split(|c| c == ',' || c == ';').filter_map(|token| {
+        let token = token.trim();
+        let token = match token.get(..4) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("for=") => &token[4..],
+            _ => token,
+        };
+        let token = token.trim_matches('"');
+        token
+            .parse::<IpAddr>()
+            .ok()
+            .or_else(|| token.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+            .or_else(|| {
+                token
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .ok()
+            })
+    })
+}
+
+/// judge 返回的数据, 格式与 httpbin 的 /get 相同
+#[derive(Debug, Deserialize)]
+pub struct JudgeResponse {
+    /// judge 看到的来源 IP
+    pub origin: String,
+    /// judge 收到的请求头
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl JudgeResponse {
+    /// 请求头名称不区分大小写
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 根据 judge 看到的内容判断匿名程度
+    pub fn anonymity(&self, real_ip: &str) -> AnonymityLevel {
+        let real_ips = parse_ips(real_ip).collect::<Vec<_>>();
+        let contains = |value: &str| parse_ips(value).any(|ip| real_ips.contains(&ip));
+        let leaked = contains(&self.origin)
+            || LEAK_HEADERS
+                .iter()
+                .any(|name| self.header(name).map_or(false, contains));
+        if leaked {
+            AnonymityLevel::Transparent
+        } else if PROXY_HEADERS.iter().any(|name| self.header(name).is_some()) {
+            AnonymityLevel::Anonymous
+        } else {
+            AnonymityLevel::Elite
+        }
+    }
+
+    /// 出口 IP, origin 带有 X-Forwarded-For 时(如 httpbin)最后一个才是直接连接 judge 的地址
+    pub fn exit_ip(&self) -> Option<IpAddr> {
+        parse_ips(&self.origin).last()
+    }
+}
+
+/// 不通过代理访问 judge, 获取本机的公网 IP
+pub async fn get_real_ip(judge_url: &str, timeout: u64) -> SpiderResult<String> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .build()?;
+    let judge: JudgeResponse = client.get(judge_url).send().await?.json().await?;
+    Ok(judge.origin)
+}
+
+/// 通过代理访问 judge, 验证代理真实的匿名程度, 同时返回 judge 看到的出口 IP
+pub async fn check_anonymity(
+    proxy: &Proxy,
+    judge_url: &str,
+    real_ip: &str,
+    timeout: u64,
+) -> SpiderResult<(AnonymityLevel, Option<IpAddr>)> {
+    let client = build_client(proxy, timeout)?;
+    let judge: JudgeResponse = client.get(judge_url).send().await?.json().await?;
+    Ok((judge.anonymity(real_ip), judge.exit_ip()))
+}
@@ -30,7 +30,7 @@ pub fn spider_thread(proxy_pool: AProxyPool, spider_config: &SpiderConfig) {
             }
             Ok(v) => v,
         };
-        proxy_pool.clone().extend_unstable(proxies);
+        proxy_pool.clone().extend_unstable(proxies, name);
     }
 
     for rules in &spider_config.common_regex {
@@ -41,19 +41,19 @@ pub fn spider_thread(proxy_pool: AProxyPool, spider_config: &SpiderConfig) {
             ip,
             port,
             anonymity,
-            ssl_type,
+            protocol,
         } = rules;
         if !enable {
             continue;
         }
-        let proxies = match regex_getter(name, urls, ip, port, anonymity, ssl_type) {
+        let proxies = match regex_getter(name, urls, ip, port, anonymity, protocol) {
             Err(e) => {
                 error!("{}", e);
                 vec![]
             }
             Ok(v) => v,
         };
-        proxy_pool.clone().extend_unstable(proxies);
+        proxy_pool.clone().extend_unstable(proxies, name);
     }
     info!("代理爬取结束");
 }